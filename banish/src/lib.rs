@@ -5,11 +5,93 @@
 //!
 //! ## Syntax
 //! - **@state** : Defines a state that loops until no rules trigger or a state transition. States execute from top to bottom.
+//! - **@state abstract** : Defines a state that is never entered and takes no index. It only holds rules for other states to inherit.
+//! - **@state : @base** : Inherits every rule of `@base`, which run before the state's own rules. Rule names must not collide with inherited ones.
+//! - **@state timeout(N) => @target** : Transitions to `@target` once a single visit to the state has made `N` passes over its rules without leaving it. Transitions and returns made before then are unaffected.
+//! - **@state #[tag("name")]** : Attaches one or more tags to a state for tooling. Tags do not change how the machine runs, and with the `machine` option `machine.tags()` lists those of the current state.
+//! - **@state #[cfg(predicate)]** : Only compiles the state when the predicate holds. States keep their declaration-order index either way; an excluded state is skipped by fall-through, and transitioning to it is a compile error.
 //! - **rule ? condition {}** : Defines a rule. Executes if its condition is true. Rules execute from top to bottom.
 //! - **!? {}** : Defines an else clause after the closing brace of a rule with a condition.
 //! - **rule ? {}** : A rule without a condition. Executes exactly once per state entry. Cannot have an else clause.
-//! - **=> @state;** : Transitions immediately to another state, but is a rule top-level statement only.
-//! - **return value;** : Immediately exit banish and return a value if passed.
+//! - **rule ?! {}** : A rearmable rule without a condition. Runs once per state entry, and once more after each `rearm rule;`.
+//! - **rule ? condition every N {}** : Runs the body only on every Nth pass where the condition is true, counting from state entry. Passes that are skipped still keep the state running.
+//! - **switch value { pattern => {} }** : Runs the first arm whose pattern matches, every iteration like a conditioned rule. Arms are exclusive, can have an `if` guard like a `match` arm (`pattern if condition => {}`), and switches are named `switch_0`, `switch_1`, ... within their state. A matching arm keeps the state running, so with a catch-all arm such as `_ => {}` the state never settles unless that arm transitions or returns.
+//! - **=> @state;** : Transitions immediately to another state, but is a rule top-level statement only (including the top level of an `!?` clause).
+//! - **=> @state if condition;** : Transitions only when the condition is true, otherwise the rule body continues. Same placement rules as `=> @state;`.
+//! - **=> @state else @fallback;** : Transitions to `@state`, or to `@fallback` when `@state` is compiled out by its cfg or not declared at all. It is only an error when neither exists. Can be combined with `if condition`.
+//! - **machine.transition_to(@state);** : Method-style alternative to `=> @state;`, with the same placement rules.
+//! - **rearm rule;** : Lets a `?!` rule of the current state run again on the next pass, but is a rule top-level statement only.
+//! - **yield value;** : In an `iter` machine, produces `value` as the next item, but is a rule top-level statement only (not inside `switch` arms).
+//! - **return value;** : Immediately exit banish and return a value if passed. Any Rust value can be returned, such as a tuple for several outputs. When no `return` carries a value, banish evaluates to `()`.
+//!
+//! ## Reuse
+//! Every run starts at the first state, with rule bookkeeping such as conditionless rules, `?!` rules and `every` counts reset on each state entry.
+//! Variables declared outside the macro, or the fields of `self` in an `impl` machine, belong to the caller and keep whatever the last run left in them.
+//! Resetting those is enough to run a machine again, for example by calling `run` twice or by evaluating `banish!` inside a function or closure.
+//!
+//! ## Reserved Names
+//! The generated machine keeps its own variables, such as `__current_state` and `__interaction`, next to rule code.
+//! Conditions and rule bodies should not use those names, so using one is a compile warning, or an error with the `strict` option.
+//!
+//! ## Options
+//! Options are listed before a braced body, separated by commas: `banish!(option: value { @state ... })`.
+//! - **-> Type** : Annotates the return type of the machine, for when it cannot be inferred from the `return` statements.
+//! - **impl Trait for Type** : Expands to `impl Trait for Type` instead of running the machine. The trait is expected to declare `type Output;` and `fn run(&mut self) -> Self::Output;`, and the machine becomes the body of `run`: rule bodies use `self.field` as their variables and `return value;` returns from `run`. `Output` is the `-> T` type, or `()` when no `return` carries a value. Cannot be combined with `iter`.
+//! - **history(buf, N)** : Pushes the index of every state entered onto `buf`, a `&mut VecDeque<usize>`, keeping only the last `N` entries by dropping the oldest. `N` is an integer literal above zero.
+//! - **ctx: &value** : Binds a shared reference as `ctx`, readable from every condition and body. The caller keeps ownership of the value, while the variables bodies write are captured as usual.
+//! - **on_transition: callback** : Calls `callback(from, to)`, an `FnMut(usize, usize)` of state indices such as an inline closure, on every transition and fall-through. It runs before control moves to the next state. Falling through the final state without `ring` ends the machine, so it does not call the callback.
+//! - **coverage: set** : Inserts into `set`, a `&mut HashSet<&'static str>`, the name of every state entered as `"state"` and of every rule or switch arm that runs as `"state.rule"`. Comparing it against the expected names after a run shows which paths an input never exercised.
+//! - **detect_cycles: snapshot** : Evaluates `snapshot`, any `Hash` expression such as a tuple of the variables that drive the machine, on every state entry. Entering a state again with the same snapshot as one of the last 64 entries panics with the repeating sequence of states, or gives `Err(BanishError::Cycle)` with `catch`. Each entry costs one hash and a scan of those 64 entries, and memory stays fixed, so a cycle longer than 64 entries goes unnoticed.
+//! - **catch** : The machine evaluates to `Result<T, BanishError>` instead of `T`. Every `return value;` becomes `Ok(value)`, and falling through the final state gives `Err(BanishError::NoReturn)` instead of panicking, as do the failures of `detect_cycles` and `assert_converges`. Without any `return`, the `Ok` type is left to inference. Cannot be combined with `iter`.
+//! - **assert_converges(max = passes)** : In debug builds, panics naming the state when a single visit to a state makes more than `passes` passes over its rules, or gives `Err(BanishError::NoConvergence)` with `catch`. Release builds compile the check away.
+//! - **metrics: &mut value** : Calls the hooks of the [`Metrics`] trait on `value` as the machine runs: state entry, every pass, every rule that runs, state exit and return. Unimplemented hooks do nothing.
+//! - **ring** : Falling through the final state wraps around to the first state instead of ending the machine, so cyclic machines need no transition back. The machine then only stops at a `return`.
+//! - **strict** : Turns warnings into compile errors. Without it, banish warns about a rule whose condition is built only from literals and is always false, such as `false` or `1 == 2`, since the rule can never run, and about rule code using a reserved name.
+//! - **total** : Rejects the machine at compile time unless every state has a path, through transitions or fall-through, to a `return`. Conditions are not evaluated, so this catches structural non-termination only.
+//! - **machine** : Binds a `machine` handle in every condition and body. `machine.iteration()` counts the passes already made in the current state visit, `machine.state()` names the current state and `machine.tags()` returns its tags. The handle is a copy rebuilt each pass.
+//! - **iter** : Turns the machine into an `Iterator` instead of running it. Each `yield value;` produces the next item, and the following call to `next` resumes right after it. Variables are moved into the iterator and persist between calls, but `let` bindings made in a body before a `yield` do not survive it. Falling through the final state or `return None;` ends the iterator, and `-> T` sets the item type.
+//!
+//! ## Testing
+//! `banish_test!` runs a machine once and asserts on how it ended. Initial variables are supplied as `let` statements before the machine, which reads and writes them as usual.
+//! `expect value;` compares the return value, `expect in @state;` checks the state the machine returned from, and `expect value in @state;` checks both.
+//! The machine follows, either as bare states or as `options { states }`, and cannot use the `history`, `iter` or `impl` options. A failed assertion lists the states the machine visited.
+//! ```rust
+//! use banish::banish_test;
+//!
+//! banish_test! {
+//!     let mut ticks: i32 = 0;
+//!     let mut loop_count: i32 = 0;
+//!     expect 2 in @yellow;
+//!
+//!     @red
+//!         announce ? { ticks = 0; loop_count += 1; }
+//!         timer ? ticks < 3 { ticks += 1; }
+//!
+//!     @green
+//!         timer ? ticks < 6 { ticks += 1; }
+//!
+//!     @yellow
+//!         timer ? ticks < 10 { ticks += 1; }
+//!         reset ? ticks == 10 && loop_count < 2 { => @red; }
+//!         stop ? ticks == 10 && loop_count == 2 { return loop_count; }
+//! }
+//! ```
+//!
+//! Here the machine returns from `@b`, so the test fails and reports `a -> b`.
+//! ```should_panic
+//! use banish::banish_test;
+//!
+//! banish_test! {
+//!     let n: i32 = 1;
+//!     expect in @a;
+//!
+//!     @a
+//!         go ? n > 0 { => @b; }
+//!
+//!     @b
+//!         done ? { return; }
+//! }
+//! ```
 //!
 //! ## Examples
 //! https://github.com/LoganFlaherty/banish/blob/main/docs/README.md
@@ -56,5 +138,923 @@
 //!     }
 //! }
 //! ```
+//!
+//! ### Transitions
+//! Declaration order does not restrict transitions, so a state can move to one declared before or after it.
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut trail: Vec<&str> = Vec::new();
+//! let mut volleys: i32 = 0;
+//! let result: Vec<&str> = banish! {
+//!     @ping
+//!         hit ? {
+//!             trail.push("ping");
+//!             => @pong;
+//!         }
+//!
+//!     @pong
+//!         hit ? volleys < 2 {
+//!             trail.push("pong");
+//!             volleys += 1;
+//!             => @ping;
+//!         } !? { return trail; }
+//! };
+//! assert_eq!(result, ["ping", "pong", "ping", "pong", "ping"]);
+//! ```
+//!
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! banish! {
+//!     @start
+//!         go ? { => @missing; }
+//! }
+//! ```
+//!
+//! ### Tags
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut polls: i32 = 0;
+//! let tags: &[&str] = banish!(machine {
+//!     @waiting #[tag("input-waiting")]
+//!         poll ? polls < 2 { polls += 1; }
+//!
+//!     @stopped #[tag("terminal", "quiet")]
+//!         stop ? { return machine.tags(); }
+//! });
+//! assert_eq!(tags, ["terminal", "quiet"]);
+//! ```
+//!
+//! ### Timeouts
+//! ```rust
+//! use banish::banish;
+//!
+//! let connected: bool = false;
+//! let mut attempts: u32 = 0;
+//! let result: (&str, u32) = banish! {
+//!     @connecting timeout(5) => @failed
+//!         retry ? !connected { attempts += 1; }
+//!         done ? connected { return ("connected", attempts); }
+//!
+//!     @failed
+//!         give_up ? { return ("timed out", attempts); }
+//! };
+//! assert_eq!(result, ("timed out", 5));
+//! ```
+//!
+//! Like the count of `every`, the pass count may carry any integer suffix.
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut polls: u8 = 0;
+//! let polls: u8 = banish! {
+//!     @waiting timeout(3u8) => @done
+//!         poll ? polls < 100 { polls += 1; }
+//!
+//!     @done
+//!         stop ? { return polls; }
+//! };
+//! assert_eq!(polls, 3);
+//! ```
+//!
+//! ### Inheritance
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut fuel: i32 = 3;
+//! let mut log: Vec<&str> = Vec::new();
+//! let log = banish! {
+//!     @common abstract
+//!         abort ? fuel == 0 { log.push("abort"); return log; }
+//!
+//!     @climb : @common
+//!         burn ? fuel > 0 { fuel -= 1; log.push("climb"); }
+//!
+//!     @cruise : @common
+//!         coast ? { log.push("cruise"); return log; }
+//! };
+//! assert_eq!(log, ["climb", "climb", "climb", "abort"]);
+//! ```
+//!
+//! Abstract states are checked like any other, even when no state inherits from them.
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! banish! {
+//!     @base abstract
+//!         bad ? { }
+//!         bad ? { => @nowhere; }
+//!
+//!     @start
+//!         done ? { return; }
+//! }
+//! ```
+//!
+//! ### Conditional States
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut log: Vec<&str> = Vec::new();
+//! let log = banish! {
+//!     @start
+//!         begin ? { log.push("start"); }
+//!
+//!     // `any()` never holds, so this state is compiled out
+//!     @never #[cfg(any())]
+//!         skipped ? { log.push("never"); }
+//!
+//!     @finish
+//!         done ? { log.push("finish"); return log; }
+//! };
+//! assert_eq!(log, ["start", "finish"]);
+//! ```
+//!
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! banish! {
+//!     @start
+//!         begin ? { => @never; }
+//!
+//!     @never #[cfg(any())]
+//!         done ? { return; }
+//! }
+//! ```
+//!
+//! ### Fallback Transitions
+//! ```rust
+//! use banish::banish;
+//!
+//! let route = |fast: bool| banish! {
+//!     @start
+//!         pick ? fast { => @fast else @slow; } !? { => @slow; }
+//!
+//!     @fast
+//!         done ? { return "fast"; }
+//!
+//!     @slow
+//!         done ? { return "slow"; }
+//! };
+//! assert_eq!(route(true), "fast");
+//!
+//! // With `@fast` compiled out, the same transition takes the fallback
+//! let result: &str = banish! {
+//!     @start
+//!         pick ? { => @fast else @slow; }
+//!
+//!     @fast #[cfg(any())]
+//!         done ? { return "fast"; }
+//!
+//!     @slow
+//!         done ? { return "slow"; }
+//! };
+//! assert_eq!(result, "slow");
+//! ```
+//!
+//! ### Reserved Names
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! banish!(strict {
+//!     @start
+//!         go ? {
+//!             let __interaction = 5;
+//!             return;
+//!         }
+//! });
+//! ```
+//!
+//! ### Switch
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut code: i32 = 1;
+//! let mut log: Vec<&str> = Vec::new();
+//! let log = banish! {
+//!     @dispatch
+//!         switch code {
+//!             1 => { log.push("one"); code = 2; }
+//!             2 => { log.push("two"); code = 7; }
+//!             _ => { log.push("other"); => @done; }
+//!         }
+//!
+//!     @done
+//!         finish ? { return log; }
+//! };
+//! assert_eq!(log, ["one", "two", "other"]);
+//! ```
+//!
+//! Arms can have guards, and the catch-all arm transitions so the state does not run forever.
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut code: i32 = 1;
+//! let mut ready: bool = false;
+//! let mut log: Vec<&str> = Vec::new();
+//! let log = banish! {
+//!     @dispatch
+//!         switch code {
+//!             1 if ready => { log.push("ready"); code = 2; }
+//!             1 => { log.push("waiting"); ready = true; }
+//!             _ => { => @done; }
+//!         }
+//!
+//!     @done
+//!         finish ? { return log; }
+//! };
+//! assert_eq!(log, ["waiting", "ready"]);
+//! ```
+//!
+//! ### Shadowed Transitions
+//! A rule that always runs on entry and always transitions means no later rule of that kind in the state can transition, so this is rejected.
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! banish! {
+//!     @start
+//!         first ? { => @a; }
+//!         second ? true { => @b; }
+//!
+//!     @a
+//!         done ? { return; }
+//!
+//!     @b
+//!         done ? { return; }
+//! }
+//! ```
+//!
+//! ### Guarded Transitions
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut steps: Vec<&str> = Vec::new();
+//! let retries: i32 = 0;
+//! let steps = banish! {
+//!     @attempt
+//!         run ? {
+//!             steps.push("attempt");
+//!             => @retry if retries > 0;
+//!             steps.push("give up");
+//!             return steps;
+//!         }
+//!
+//!     @retry
+//!         again ? { return steps; }
+//! };
+//! assert_eq!(steps, ["attempt", "give up"]);
+//! ```
+//!
+//! ### Rearming
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut setups: i32 = 0;
+//! let mut value: i32 = 0;
+//! let result: i32 = banish! {
+//!     @work
+//!         setup ?! { setups += 1; value = 0; }
+//!         grow ? value < 3 { value += 1; }
+//!         retry ? value == 3 && setups < 2 { rearm setup; }
+//!         done ? value == 3 && setups == 2 { return setups; }
+//! };
+//! assert_eq!(result, 2);
+//! ```
+//!
+//! ### Every
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut ticks: i32 = 0;
+//! let mut checkpoints: Vec<i32> = Vec::new();
+//! let saved: Vec<i32> = banish! {
+//!     @run
+//!         tick ? ticks < 10 { ticks += 1; }
+//!         save ? ticks < 10 every 3 { checkpoints.push(ticks); }
+//!         done ? ticks == 10 { return checkpoints; }
+//! };
+//! assert_eq!(saved, [3, 6, 9]);
+//! ```
+//!
+//! The count is only a number of passes, so a suffix such as `u8` does not change its type.
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut ticks: i32 = 0;
+//! let mut saves: i32 = 0;
+//! let saves: i32 = banish! {
+//!     @run
+//!         tick ? ticks < 5 { ticks += 1; }
+//!         save ? ticks < 5 every 2u8 { saves += 1; }
+//!         done ? ticks == 5 { return saves; }
+//! };
+//! assert_eq!(saves, 2);
+//! ```
+//!
+//! ### Else Transitions
+//! ```rust
+//! use banish::banish;
+//!
+//! let ready: bool = false;
+//! let result: &str = banish! {
+//!     @check
+//!         go ? ready {
+//!             return "ready";
+//!         } !? { => @wait; }
+//!
+//!     @wait
+//!         done ? { return "waited"; }
+//! };
+//! assert_eq!(result, "waited");
+//! ```
+//!
+//! ### Unit Machines
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut ticks: i32 = 0;
+//! let outcome = banish! {
+//!     @tick
+//!         step ? ticks < 3 { ticks += 1; }
+//!         stop ? ticks == 3 { return; }
+//! };
+//! let () = outcome;
+//! ```
+//!
+//! A machine without any `return` can only end by panicking, so it fits wherever a value is expected.
+//! ```should_panic
+//! use banish::banish;
+//!
+//! let mut ticks: i32 = 0;
+//! let limit: i32 = banish! {
+//!     @tick
+//!         step ? ticks < 3 { ticks += 1; }
+//! };
+//! ```
+//!
+//! ### Return Type
+//! ```rust
+//! use banish::banish;
+//!
+//! let limit: u32 = 4;
+//! let mut count: u32 = 0;
+//! let total = banish!(-> u64 {
+//!     @count
+//!         step ? count < limit { count += 1; }
+//!         done ? count == limit { return count.into(); }
+//!
+//!     @unreachable
+//!         fail ? { return 0; }
+//! });
+//! assert_eq!(total, 4u64);
+//! ```
+//!
+//! ```rust
+//! use banish::banish;
+//!
+//! let words = ["alpha", "beta"];
+//! let mut idx: usize = 0;
+//! let (count, joined) = banish!(-> (i32, String) {
+//!     @join
+//!         next ? idx < words.len() { idx += 1; }
+//!         done ? idx == words.len() {
+//!             return (idx as i32, words.join("-"));
+//!         }
+//! });
+//! assert_eq!((count, joined.as_str()), (2, "alpha-beta"));
+//! ```
+//!
+//! ### Trait Implementation
+//! ```rust
+//! use banish::banish;
+//!
+//! trait StateMachine {
+//!     type Output;
+//!     fn run(&mut self) -> Self::Output;
+//! }
+//!
+//! struct Door { opens: u32, locked: bool }
+//!
+//! banish!(impl StateMachine for Door, -> u32 {
+//!     @closed
+//!         unlock ? self.locked { self.locked = false; }
+//!         open ? !self.locked && self.opens < 2 { self.opens += 1; => @opened; }
+//!         done ? self.opens == 2 { return self.opens; }
+//!
+//!     @opened
+//!         close ? { => @closed; }
+//! });
+//!
+//! let mut door = Door { opens: 0, locked: true };
+//! assert_eq!(door.run(), 2);
+//! assert!(!door.locked);
+//!
+//! // Resetting the fields is enough to run the machine again from scratch
+//! door.opens = 0;
+//! door.locked = true;
+//! assert_eq!(door.run(), 2);
+//! ```
+//!
+//! A `return` inside a closure belongs to the closure, so this machine still has `()` as its `Output`.
+//! ```rust
+//! use banish::banish;
+//!
+//! trait StateMachine {
+//!     type Output;
+//!     fn run(&mut self) -> Self::Output;
+//! }
+//!
+//! struct Cleaner { values: Vec<i32>, done: bool }
+//!
+//! banish!(impl StateMachine for Cleaner {
+//!     @clean
+//!         filter ? !self.done {
+//!             self.values.retain(|value| {
+//!                 if *value < 0 { return false; }
+//!                 true
+//!             });
+//!             self.done = true;
+//!         }
+//!         stop ? self.done { return; }
+//! });
+//!
+//! let mut cleaner = Cleaner { values: vec![1, -2, 3], done: false };
+//! let () = cleaner.run();
+//! assert_eq!(cleaner.values, [1, 3]);
+//! ```
+//!
+//! The `Output` type cannot be inferred, so returning a value needs `-> T`, with or without `catch`.
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! trait StateMachine {
+//!     type Output;
+//!     fn run(&mut self) -> Self::Output;
+//! }
+//!
+//! struct Counter { count: u32 }
+//!
+//! banish!(impl StateMachine for Counter, catch {
+//!     @count
+//!         grow ? self.count < 3 { self.count += 1; }
+//!         done ? self.count == 3 { return self.count; }
+//! });
+//! ```
+//!
+//! ### History
+//! ```rust
+//! use banish::banish;
+//! use std::collections::VecDeque;
+//!
+//! let mut trail: VecDeque<usize> = VecDeque::new();
+//! let mut count: i32 = 0;
+//! banish!(history(&mut trail, 3) {
+//!     @ping
+//!         hit ? { count += 1; => @pong; }
+//!
+//!     @pong
+//!         hit ? count < 3 { => @ping; } !? { return; }
+//! });
+//!
+//! // Only the last three entries are kept
+//! assert_eq!(trail, [1, 0, 1]);
+//! ```
+//!
+//! A bound of zero would record nothing, so it is rejected.
+//! ```compile_fail
+//! use banish::banish;
+//! use std::collections::VecDeque;
+//!
+//! let mut trail: VecDeque<usize> = VecDeque::new();
+//! banish!(history(&mut trail, 0) {
+//!     @start
+//!         done ? { return; }
+//! });
+//! ```
+//!
+//! ### Transition Callback
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut transitions: Vec<(usize, usize)> = Vec::new();
+//! let record = |from, to| transitions.push((from, to));
+//! let mut laps: i32 = 0;
+//! banish!(on_transition: record {
+//!     @a
+//!         lap ? { laps += 1; }
+//!
+//!     @b
+//!         again ? laps < 2 { => @a; }
+//!
+//!     @c
+//!         stop ? { return; }
+//! });
+//! assert_eq!(transitions, [(0, 1), (1, 0), (0, 1), (1, 2)]);
+//! ```
+//!
+//! The callback can also be written inline. Falling off the final state is not a transition, so it is not reported.
+//! ```rust
+//! use banish::{banish, BanishError};
+//!
+//! let mut transitions: Vec<(usize, usize)> = Vec::new();
+//! let result: Result<(), BanishError> = banish!(catch, on_transition: |from, to| transitions.push((from, to)) {
+//!     @a
+//!         step ? {}
+//!
+//!     @b
+//!         step ? {}
+//! });
+//! assert_eq!(result, Err(BanishError::NoReturn));
+//! assert_eq!(transitions, [(0, 1)]);
+//! ```
+//!
+//! ### Metrics
+//! ```rust
+//! use banish::{banish, Metrics};
+//!
+//! #[derive(Default)]
+//! struct Log(Vec<String>);
+//!
+//! impl Metrics for Log {
+//!     fn on_enter(&mut self, state: usize) { self.0.push(format!("enter {}", state)); }
+//!     fn on_rule(&mut self, state: usize, rule: &str) { self.0.push(format!("{} {}", state, rule)); }
+//!     fn on_exit(&mut self, state: usize) { self.0.push(format!("exit {}", state)); }
+//!     fn on_return(&mut self) { self.0.push("return".to_string()); }
+//! }
+//!
+//! let mut log: Log = Log::default();
+//! let mut ticks: i32 = 0;
+//! banish!(metrics: &mut log {
+//!     @count
+//!         tick ? ticks < 2 { ticks += 1; }
+//!
+//!     @done
+//!         stop ? { return; }
+//! });
+//! assert_eq!(log.0, [
+//!     "enter 0", "0 tick", "0 tick", "exit 0",
+//!     "enter 1", "1 stop", "return",
+//! ]);
+//! ```
+//!
+//! An `iter` machine calls `on_return` once it is exhausted, whether by `return None;` or by falling through its final state.
+//! ```rust
+//! use banish::{banish, Metrics};
+//!
+//! #[derive(Default)]
+//! struct Returns(u32);
+//!
+//! impl Metrics for Returns {
+//!     fn on_return(&mut self) { self.0 += 1; }
+//! }
+//!
+//! let mut returns: Returns = Returns::default();
+//! let mut n: u32 = 0;
+//! let items: Vec<u32> = banish!(-> u32, iter, metrics: &mut returns {
+//!     @count
+//!         next ? n < 2 { n += 1; yield n; }
+//!         stop ? n == 2 { return None; }
+//! }).collect();
+//! assert_eq!(items, [1, 2]);
+//! assert_eq!(returns.0, 1);
+//!
+//! let items: Vec<u32> = banish!(-> u32, iter, metrics: &mut returns {
+//!     @once
+//!         only ? { yield 7; }
+//! }).collect();
+//! assert_eq!(items, [7]);
+//! assert_eq!(returns.0, 2);
+//! ```
+//!
+//! ### Coverage
+//! ```rust
+//! use banish::banish;
+//! use std::collections::HashSet;
+//!
+//! let classify = |value: i32, covered: &mut HashSet<&'static str>| banish!(coverage: covered {
+//!     @check
+//!         negative ? value < 0 { => @reject; }
+//!         positive ? { return "accepted"; }
+//!
+//!     @reject
+//!         done ? { return "rejected"; }
+//! });
+//!
+//! let mut covered: HashSet<&str> = HashSet::new();
+//! classify(5, &mut covered);
+//! assert!(!covered.contains("reject"));
+//!
+//! classify(-5, &mut covered);
+//! let all: HashSet<&str> = HashSet::from(["check", "check.negative", "check.positive", "reject", "reject.done"]);
+//! assert_eq!(covered, all);
+//! ```
+//!
+//! ### Cycle Detection
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut n: i32 = 0;
+//! let result: i32 = banish!(detect_cycles: n {
+//!     @a
+//!         go ? { n += 1; => @b; }
+//!
+//!     @b
+//!         back ? n < 3 { => @a; } !? { return n; }
+//! });
+//! assert_eq!(result, 3);
+//! ```
+//!
+//! Here `n` never changes, so re-entering `@a` panics instead of looping forever.
+//! ```should_panic
+//! use banish::banish;
+//!
+//! let n: i32 = 0;
+//! banish!(detect_cycles: n {
+//!     @a
+//!         go ? { => @b; }
+//!
+//!     @b
+//!         back ? n < 3 { => @a; } !? { return; }
+//! });
+//! ```
+//!
+//! ### Convergence Assertions
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut n: i32 = 0;
+//! let result: i32 = banish!(assert_converges(max = 10) {
+//!     @count
+//!         grow ? n < 5 { n += 1; }
+//!         done ? n == 5 { return n; }
+//! });
+//! assert_eq!(result, 5);
+//! ```
+//!
+//! Here `grow` keeps `@count` busy for far longer than ten passes, so debug builds panic.
+//! ```should_panic
+//! use banish::banish;
+//!
+//! let mut n: i32 = 0;
+//! banish!(assert_converges(max = 10) {
+//!     @count
+//!         grow ? n < 1000 { n += 1; }
+//!         done ? n == 1000 { return; }
+//! });
+//! ```
+//!
+//! ### Catch
+//! ```rust
+//! use banish::{banish, BanishError};
+//!
+//! let run = |limit: i32| {
+//!     let mut n: i32 = 0;
+//!     banish!(catch {
+//!         @count
+//!             grow ? n < limit { n += 1; }
+//!
+//!         @check
+//!             done ? n == 3 { return n; }
+//!     })
+//! };
+//! assert_eq!(run(3), Ok(3));
+//! assert_eq!(run(5), Err(BanishError::NoReturn));
+//!
+//! // A machine without any `return` can only fail, so its `Ok` type comes from the context
+//! let mut k: i32 = 0;
+//! let result: Result<i32, BanishError> = banish!(catch {
+//!     @count
+//!         grow ? k < 2 { k += 1; }
+//! });
+//! assert_eq!(result, Err(BanishError::NoReturn));
+//! ```
+//!
+//! The failures of `detect_cycles` and `assert_converges` become errors as well.
+//! ```rust
+//! use banish::{banish, BanishError};
+//!
+//! let n: i32 = 0;
+//! let result: Result<(), BanishError> = banish!(catch, detect_cycles: n {
+//!     @a
+//!         go ? { => @b; }
+//!
+//!     @b
+//!         back ? n < 3 { => @a; } !? { return; }
+//! });
+//! assert_eq!(result, Err(BanishError::Cycle { state: "a" }));
+//!
+//! let mut m: i32 = 0;
+//! let result: Result<i32, BanishError> = banish!(catch, assert_converges(max = 10) {
+//!     @count
+//!         grow ? m < 1000 { m += 1; }
+//!         done ? m == 1000 { return m; }
+//! });
+//! assert_eq!(result, Err(BanishError::NoConvergence { state: "count", passes: 10 }));
+//! ```
+//!
+//! ### Ring
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut lights: Vec<&str> = Vec::new();
+//! let lights = banish!(ring {
+//!     @red
+//!         show ? { lights.push("red"); }
+//!         stop ? lights.len() > 6 { return lights; }
+//!
+//!     @green
+//!         show ? { lights.push("green"); }
+//!
+//!     @yellow
+//!         show ? { lights.push("yellow"); }
+//! });
+//! assert_eq!(lights, ["red", "green", "yellow", "red", "green", "yellow", "red"]);
+//! ```
+//!
+//! ### Context
+//! ```rust
+//! use banish::banish;
+//!
+//! struct Settings { threshold: i32, step: i32 }
+//!
+//! let settings = Settings { threshold: 10, step: 4 };
+//! let mut level: i32 = 0;
+//! let result: i32 = banish!(ctx: &settings {
+//!     @fill
+//!         rise ? level < ctx.threshold { level += ctx.step; }
+//!         done ? level >= ctx.threshold { return level; }
+//! });
+//! assert_eq!(result, 12);
+//! assert_eq!(settings.threshold, 10);
+//! ```
+//!
+//! ### Machine Handle
+//! ```rust
+//! use banish::banish;
+//!
+//! let name: &str = banish!(machine {
+//!     @warmup
+//!         tick ? machine.iteration() < 3 {}
+//!         next ? machine.iteration() == 3 {
+//!             machine.transition_to(@ready);
+//!         }
+//!
+//!     @ready
+//!         report ? { return machine.state(); }
+//! });
+//! assert_eq!(name, "ready");
+//! ```
+//!
+//! ### Iterator
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut n: u32 = 0;
+//! let mut items = banish!(-> u32, iter {
+//!     @produce
+//!         pair ? n < 3 {
+//!             yield n;
+//!             yield n * 10;
+//!             n += 1;
+//!         }
+//!
+//!     @finish
+//!         last ? { yield 100; }
+//! });
+//!
+//! // Nothing runs until the iterator is polled
+//! assert_eq!(items.next(), Some(0));
+//! assert_eq!(items.collect::<Vec<u32>>(), [0, 1, 10, 2, 20, 100]);
+//! ```
+//!
+//! Transitions enter the target state afresh, just like outside an iterator.
+//! ```rust
+//! use banish::banish;
+//! use std::collections::VecDeque;
+//!
+//! let mut rounds: u32 = 0;
+//! let mut trail: VecDeque<usize> = VecDeque::new();
+//! let items: Vec<u32> = banish!(-> u32, history(&mut trail, 8), iter {
+//!     @ping
+//!         serve ? { yield 1; }
+//!         send ? rounds < 2 { rounds += 1; => @pong; }
+//!
+//!     @pong
+//!         serve ? { yield 2; => @ping if rounds < 2; }
+//! }).collect();
+//! assert_eq!(items, [1, 2, 1, 2]);
+//! assert_eq!(trail, [0, 1, 0, 1]);
+//! ```
+//!
+//! ### Strict
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! banish!(strict {
+//!     @start
+//!         never ? 1 == 2 { return; }
+//!         done ? { return; }
+//! });
+//! ```
+//!
+//! ### Total
+//! ```rust
+//! use banish::banish;
+//!
+//! let mut attempts: i32 = 0;
+//! let result: i32 = banish!(total {
+//!     @retry
+//!         attempt ? attempts < 3 { attempts += 1; }
+//!
+//!     @finish
+//!         done ? { return attempts; }
+//! });
+//! assert_eq!(result, 3);
+//! ```
+//!
+//! `@spin` can only transition to itself, so it is rejected.
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! banish!(total {
+//!     @start
+//!         begin ? { => @spin; }
+//!
+//!     @spin
+//!         again ? { => @spin; }
+//!
+//!     @end
+//!         done ? { return; }
+//! });
+//! ```
+//!
+//! `@ping` can return, but `serve` always transitions before `done` gets a chance, so the two states bounce forever.
+//! ```compile_fail
+//! use banish::banish;
+//!
+//! let mut rally: i32 = 0;
+//! banish!(total {
+//!     @ping
+//!         serve ? { => @pong; }
+//!         done ? rally > 3 { return; }
+//!
+//!     @pong
+//!         serve ? { rally += 1; => @ping; }
+//! });
+//! ```
+
+pub use banish_derive::{banish, banish_test};
+
+use std::fmt;
+
+/// Structural failures reported by a machine declared with `banish!(catch { ... })`
+/// instead of panicking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BanishError {
+    /// The machine fell through its final state without reaching a `return`.
+    NoReturn,
+
+    /// With `detect_cycles`, a state was entered again with an identical snapshot.
+    Cycle { state: &'static str },
+
+    /// With `assert_converges`, a single visit to a state made more passes than allowed.
+    NoConvergence { state: &'static str, passes: usize },
+}
+
+impl fmt::Display for BanishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanishError::NoReturn => write!(f, "No return in final state"),
+            BanishError::Cycle { state } => {
+                write!(f, "Cycle detected, state '{}' re-entered with an identical snapshot", state)
+            }
+            BanishError::NoConvergence { state, passes } => {
+                write!(f, "State '{}' did not converge within {} passes", state, passes)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BanishError {}
+
+/// Hooks called by a machine declared with `banish!(metrics: &mut value { ... })`.
+/// States are passed as their declaration-order index. Every hook defaults to doing nothing,
+/// so an implementation only overrides the events it cares about.
+pub trait Metrics {
+    /// Called when a state is entered, before any of its rules run.
+    fn on_enter(&mut self, _state: usize) {}
+
+    /// Called at the start of every pass over the rules of a state.
+    fn on_iter(&mut self, _state: usize) {}
+
+    /// Called when a rule body or switch arm runs.
+    fn on_rule(&mut self, _state: usize, _rule: &str) {}
+
+    /// Called when a state is left through a transition or fall-through.
+    fn on_exit(&mut self, _state: usize) {}
 
-pub use banish_derive::banish;
\ No newline at end of file
+    /// Called once the machine returns, or when an `iter` machine is exhausted.
+    fn on_return(&mut self) {}
+}
@@ -4,12 +4,14 @@
 //! fixed point or trigger transitions, making complex control flow easier to express and reason about.
 //! This is the macro implementation for the `banish` crate, which provides the public API and user-facing documentation.
 
-use proc_macro;
+#![allow(clippy::four_forward_slashes, clippy::large_enum_variant)]
+
 use proc_macro2::TokenTree;
-use quote::quote;
+use quote::{ToTokens, format_ident, quote, quote_spanned};
 use syn::{
-    Expr, Ident, Result, Stmt, Token, braced,
-    parse::{Parse, ParseStream}, parse_macro_input,
+    Attribute, Expr, Ident, LitStr, Pat, Result, Stmt, Token, Type, braced, parenthesized,
+    parse::{Parse, ParseStream}, parse_macro_input, punctuated::Punctuated, spanned::Spanned,
+    visit_mut::{self, VisitMut},
 };
 use std::collections::HashSet;
 
@@ -17,24 +19,77 @@ use std::collections::HashSet;
 //// AST
 
 struct Context {
+    options: Options,
     states: Vec<State>,
+    abstract_states: Vec<State>,
+}
+
+struct TestCase {
+    setup: Vec<Stmt>,
+    expected: Option<Expr>,
+    final_state: Option<Ident>,
+    machine: Context,
+}
+
+#[derive(Default)]
+struct Options {
+    impl_for: Option<(syn::Path, Type)>,
+    return_type: Option<Type>,
+    history: Option<(Expr, usize)>,
+    ctx: Option<Expr>,
+    on_transition: Option<Expr>,
+    detect_cycles: Option<Expr>,
+    metrics: Option<Expr>,
+    coverage: Option<Expr>,
+    max_passes: Option<Expr>,
+    catch: bool,
+    ring: bool,
+    strict: bool,
+    total: bool,
+    machine: bool,
+    iter: bool,
 }
 
 struct State {
     name: Ident,
+    is_abstract: bool,
+    base: Option<Ident>,
+    timeout: Option<(usize, Ident)>,
+    tags: Vec<LitStr>,
+    cfg: Option<proc_macro2::TokenStream>,
     rules: Vec<Rule>,
 }
 
+#[derive(Clone)]
 struct Rule {
     name: Ident,
     condition: Option<Expr>,
+    every: Option<usize>,
+    rearmable: bool,
     body: Vec<BanishStmt>,
     else_body: Option<Vec<BanishStmt>>,
+    switch: Option<Switch>,
+}
+
+#[derive(Clone)]
+struct Switch {
+    scrutinee: Expr,
+    arms: Vec<SwitchArm>,
+}
+
+#[derive(Clone)]
+struct SwitchArm {
+    pat: Pat,
+    guard: Option<Expr>,
+    body: Vec<BanishStmt>,
 }
 
+#[derive(Clone)]
 enum BanishStmt {
     Rust(Stmt),
-    StateTransition(Ident),
+    StateTransition(Ident, Option<Ident>, Option<Expr>),
+    Rearm(Ident),
+    Yield(Expr),
 }
 
 
@@ -42,12 +97,168 @@ enum BanishStmt {
 
 impl Parse for Context {
     fn parse(input: ParseStream) -> Result<Self> {
-        let mut states: Vec<State> = Vec::with_capacity(2);
-        while !input.is_empty() {
-            states.push(input.parse()?);
+        // Without options the states start right away: `banish! { @state ... }`
+        if input.is_empty() || input.peek(Token![@]) {
+            let (states, abstract_states) = parse_states(input)?;
+            return Ok(Context { options: Options::default(), states, abstract_states });
+        }
+
+        // Otherwise options come first and the states are braced: `banish!(history(buf, 8) { @state ... })`
+        let options: Options = input.parse()?;
+        let content: syn::parse::ParseBuffer<'_>;
+        braced!(content in input);
+        let (states, abstract_states) = parse_states(&content)?;
+        if !input.is_empty() {
+            return Err(input.error("Unexpected tokens after the banish body"));
+        }
+
+        Ok(Context { options, states, abstract_states })
+    }
+}
+
+impl Parse for TestCase {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut setup: Vec<Stmt> = Vec::new();
+        while input.peek(Token![let]) {
+            setup.push(input.parse()?);
+        }
+
+        // `expect value;`, `expect in @state;` or `expect value in @state;`
+        let keyword: Ident = input.parse()?;
+        if keyword != "expect" {
+            return Err(syn::Error::new(keyword.span(), "Expected 'expect' after the banish_test! setup"));
+        }
+        let expected: Option<Expr> = if input.peek(Token![in]) || input.peek(Token![;]) {
+            None
+        } else {
+            Some(input.parse()?)
+        };
+        let final_state: Option<Ident> = if input.peek(Token![in]) {
+            input.parse::<Token![in]>()?;
+            input.parse::<Token![@]>()?;
+            Some(input.parse()?)
+        } else { None };
+        if expected.is_none() && final_state.is_none() {
+            return Err(syn::Error::new(keyword.span(), "'expect' needs a return value, a final state or both"));
+        }
+        input.parse::<Token![;]>()?;
+
+        let machine: Context = input.parse()?;
+        Ok(TestCase { setup, expected, final_state, machine })
+    }
+}
+
+impl Parse for Options {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut options: Options = Options::default();
+        let mut seen: HashSet<String> = HashSet::new();
+
+        while !input.peek(syn::token::Brace) {
+            if input.is_empty() {
+                return Err(input.error("Unexpected end of input, expected banish body '{'"));
+            }
+
+            if input.peek(Token![impl]) {
+                let impl_token: Token![impl] = input.parse()?;
+                if options.impl_for.is_some() {
+                    return Err(syn::Error::new(impl_token.span, "Duplicate banish impl target"));
+                }
+                let trait_path: syn::Path = input.parse()?;
+                input.parse::<Token![for]>()?;
+                let self_ty: Type = input.parse()?;
+                options.impl_for = Some((trait_path, self_ty));
+                if !input.peek(syn::token::Brace) {
+                    input.parse::<Token![,]>()?;
+                }
+                continue;
+            }
+
+            if input.peek(Token![->]) {
+                let arrow: Token![->] = input.parse()?;
+                if options.return_type.is_some() {
+                    return Err(syn::Error::new(arrow.spans[0], "Duplicate banish return type"));
+                }
+                options.return_type = Some(input.parse()?);
+                if !input.peek(syn::token::Brace) {
+                    input.parse::<Token![,]>()?;
+                }
+                continue;
+            }
+
+            let name: Ident = input.parse()?;
+            if !seen.insert(name.to_string()) {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("Duplicate banish option '{}'", name),
+                ));
+            }
+
+            match name.to_string().as_str() {
+                "history" => {
+                    let args: syn::parse::ParseBuffer<'_>;
+                    parenthesized!(args in input);
+                    let buffer: Expr = args.parse()?;
+                    args.parse::<Token![,]>()?;
+                    let bound: usize = parse_positive_count(&args.parse()?, "history")?;
+                    options.history = Some((buffer, bound));
+                }
+                "ctx" => {
+                    input.parse::<Token![:]>()?;
+                    options.ctx = Some(Expr::parse_without_eager_brace(input)?);
+                }
+                "on_transition" => {
+                    input.parse::<Token![:]>()?;
+                    options.on_transition = Some(Expr::parse_without_eager_brace(input)?);
+                }
+                "detect_cycles" => {
+                    input.parse::<Token![:]>()?;
+                    options.detect_cycles = Some(Expr::parse_without_eager_brace(input)?);
+                }
+                "metrics" => {
+                    input.parse::<Token![:]>()?;
+                    options.metrics = Some(Expr::parse_without_eager_brace(input)?);
+                }
+                "coverage" => {
+                    input.parse::<Token![:]>()?;
+                    options.coverage = Some(Expr::parse_without_eager_brace(input)?);
+                }
+                "assert_converges" => {
+                    let args: syn::parse::ParseBuffer<'_>;
+                    parenthesized!(args in input);
+                    let key: Ident = args.parse()?;
+                    if key != "max" {
+                        return Err(syn::Error::new(key.span(), "Expected 'max = passes' in 'assert_converges'"));
+                    }
+                    args.parse::<Token![=]>()?;
+                    options.max_passes = Some(args.parse()?);
+                }
+                "catch" => options.catch = true,
+                "ring" => options.ring = true,
+                "strict" => options.strict = true,
+                "total" => options.total = true,
+                "machine" => options.machine = true,
+                "iter" => options.iter = true,
+                _ => {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("Unknown banish option '{}'", name),
+                    ));
+                }
+            }
+
+            if !input.peek(syn::token::Brace) {
+                input.parse::<Token![,]>()?;
+            }
         }
 
-        Ok(Context { states })
+        if options.iter && options.impl_for.is_some() {
+            return Err(input.error("The 'impl' option cannot be combined with 'iter'"));
+        }
+        if options.iter && options.catch {
+            return Err(input.error("The 'catch' option cannot be combined with 'iter'"));
+        }
+
+        Ok(options)
     }
 }
 
@@ -56,37 +267,114 @@ impl Parse for State {
         input.parse::<Token![@]>()?;
         let name: Ident = input.parse()?;
 
+        let is_abstract: bool = input.peek(Token![abstract]);
+        if is_abstract {
+            input.parse::<Token![abstract]>()?;
+        }
+
+        let base: Option<Ident> = if input.peek(Token![:]) {
+            input.parse::<Token![:]>()?;
+            input.parse::<Token![@]>()?;
+            Some(input.parse()?)
+        } else { None };
+
+        // `timeout(passes) => @target` leaves the state once it has made that many passes
+        let timeout: Option<(usize, Ident)> = if is_timeout(input) {
+            let keyword: Ident = input.parse()?;
+            if is_abstract {
+                return Err(syn::Error::new(keyword.span(), "Abstract states are never entered, so they cannot time out"));
+            }
+            let args: syn::parse::ParseBuffer<'_>;
+            parenthesized!(args in input);
+            let passes: usize = parse_positive_count(&args.parse()?, "timeout")?;
+            input.parse::<Token![=>]>()?;
+            input.parse::<Token![@]>()?;
+            Some((passes, input.parse()?))
+        } else { None };
+
+        let mut tags: Vec<LitStr> = Vec::new();
+        let mut cfgs: Vec<proc_macro2::TokenStream> = Vec::new();
+        for attr in input.call(Attribute::parse_outer)? {
+            if attr.path().is_ident("tag") {
+                let args = attr.parse_args_with(Punctuated::<LitStr, Token![,]>::parse_terminated)?;
+                tags.extend(args);
+            }
+            else if attr.path().is_ident("cfg") {
+                cfgs.push(attr.meta.require_list()?.tokens.clone());
+            }
+            else {
+                return Err(syn::Error::new_spanned(
+                    attr.path(),
+                    format!("Unknown attribute on state '{}', expected 'tag' or 'cfg'", name),
+                ));
+            }
+        }
+
+        // Several cfg attributes must all hold, just like on a Rust item
+        let cfg: Option<proc_macro2::TokenStream> = match cfgs.len() {
+            0 => None,
+            1 => cfgs.pop(),
+            _ => Some(quote! { all(#(#cfgs),*) }),
+        };
+
         let mut rules: Vec<Rule> = Vec::with_capacity(1);
         while !input.is_empty() && !input.peek(Token![@]) {
             rules.push(input.parse()?);
         }
+        name_switches(&mut rules);
 
-        Ok(State { name, rules })
+        Ok(State { name, is_abstract, base, timeout, tags, cfg, rules })
     }
 }
 
 impl Parse for Rule {
     fn parse(input: ParseStream) -> Result<Self> {
         let name: Ident = input.parse()?;
+
+        // `switch value { pattern => { ... } }`, unless `switch` is the name of an ordinary rule
+        if name == "switch" && !input.peek(Token![?]) {
+            let switch: Switch = input.parse()?;
+            return Ok(Rule {
+                name,
+                condition: None,
+                every: None,
+                rearmable: false,
+                body: Vec::new(),
+                else_body: None,
+                switch: Some(switch),
+            });
+        }
+
         input.parse::<Token![?]>()?;
 
-        let condition: Option<Expr> = if input.peek(syn::token::Brace) {
+        // `?!` directly before the body marks a rearmable rule, while `? !x {` is a negated condition
+        let rearmable: bool = input.peek(Token![!]) && input.peek2(syn::token::Brace);
+        if rearmable {
+            input.parse::<Token![!]>()?;
+        }
+
+        let mut condition_tokens: Vec<TokenTree> = collect_until_brace(input)?.into_iter().collect();
+
+        // A trailing `every N` is never valid Rust, so it can be split off the condition
+        let mut every: Option<usize> = None;
+        if let [.., TokenTree::Ident(keyword), TokenTree::Literal(count)] = condition_tokens.as_slice()
+            && keyword == "every"
+        {
+            let count: usize = parse_positive_count(&syn::parse2(TokenTree::Literal(count.clone()).into())?, "every")?;
+            if condition_tokens.len() == 2 {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("Rule '{}' cannot use 'every' without a condition.", name),
+                ));
+            }
+            condition_tokens.truncate(condition_tokens.len() - 2);
+            every = Some(count);
+        }
+
+        let condition: Option<Expr> = if condition_tokens.is_empty() {
             None
         } else {
-            let mut cond_tokens = proc_macro2::TokenStream::new();
-            
-            // Loop until we see the start of the body block
-            while !input.peek(syn::token::Brace) {
-                if input.is_empty() {
-                    return Err(input.error("Unexpected end of input, expected rule body '{'"));
-                }
-                // Pull one token at a time (e.g., "buffer", "[", "idx", "]", "==", "target")
-                cond_tokens.extend(std::iter::once(input.parse::<TokenTree>()?));
-            }
-            
-            // Now parse those isolated tokens as an Expression.
-            // Since the '{' isn't in 'cond_tokens', syn can't mistake it for a struct!
-            Some(syn::parse2(cond_tokens)?)
+            Some(syn::parse2(condition_tokens.into_iter().collect())?)
         };
 
         let content: syn::parse::ParseBuffer<'_>;
@@ -112,7 +400,37 @@ impl Parse for Rule {
             ));
         }
 
-        Ok(Rule { name, condition, body, else_body })
+        Ok(Rule { name, condition, every, rearmable, body, else_body, switch: None })
+    }
+}
+
+impl Parse for Switch {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let scrutinee: Expr = parse_expr_until_brace(input)?;
+
+        let content: syn::parse::ParseBuffer<'_>;
+        braced!(content in input);
+
+        let mut arms: Vec<SwitchArm> = Vec::new();
+        while !content.is_empty() {
+            let pat: Pat = Pat::parse_multi_with_leading_vert(&content)?;
+            let guard: Option<Expr> = if content.peek(Token![if]) {
+                content.parse::<Token![if]>()?;
+                Some(content.parse()?)
+            } else { None };
+            content.parse::<Token![=>]>()?;
+
+            let arm_content: syn::parse::ParseBuffer<'_>;
+            braced!(arm_content in content);
+            let body: Vec<BanishStmt> = parse_rule_block(&arm_content)?;
+
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+            arms.push(SwitchArm { pat, guard, body });
+        }
+
+        Ok(Switch { scrutinee, arms })
     }
 }
 
@@ -122,84 +440,755 @@ impl Parse for Rule {
 #[proc_macro]
 pub fn banish(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input: Context = parse_macro_input!(input as Context);
+    proc_macro::TokenStream::from(expand(&input))
+}
+
+// Runs a machine once and asserts on its return value and final state, reporting the states it visited on failure.
+#[proc_macro]
+pub fn banish_test(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut test: TestCase = parse_macro_input!(input as TestCase);
 
-    if let Err(err) = validate_state_and_rule_names(&input) {
-        return err.to_compile_error().into();
+    let options: &Options = &test.machine.options;
+    let conflict: Option<&str> = if options.history.is_some() {
+        Some("banish_test! records the history itself, so the 'history' option cannot be used")
+    } else if options.iter || options.impl_for.is_some() {
+        Some("banish_test! runs the machine, so the 'iter' and 'impl' options cannot be used")
+    } else { None };
+    if let Some(message) = conflict {
+        return syn::Error::new(proc_macro2::Span::call_site(), message).to_compile_error().into();
     }
 
-    let state_blocks = input.states.iter().enumerate().map(|(index, state)| {
-        let rules = state.rules.iter().map(|func| {
-            let body = func.body.iter().map(|stmt| generate_stmt(stmt, &input));
-            let else_body = func.else_body.as_ref().map(|else_block| {
-                else_block.iter().map(|stmt| generate_stmt(stmt, &input))
-            });
+    if let Some(name) = &test.final_state
+        && !test.machine.states.iter().any(|state| &state.name == name)
+    {
+        let message: String = format!("Unknown final state '{}'", name);
+        return syn::Error::new(name.span(), message).to_compile_error().into();
+    }
 
-            // If a rule has a condition, we want to run it every iteration until the condition is false.
-            if let Some(condition) = &func.condition {
-                if let Some(else_body) = else_body {
+    test.machine.options.history = Some((syn::parse_quote! { &mut __test_history }, 64));
+    let machine: proc_macro2::TokenStream = expand(&test.machine);
+    let setup = &test.setup;
+    let names = test.machine.states.iter().map(|state| state.name.to_string());
+
+    let value_check = test.expected.as_ref().map(|expected| quote! {
+        assert_eq!(__result, #expected, "banish! machine returned an unexpected value after visiting {}", __trail);
+    });
+    let final_check = test.final_state.as_ref().map(|name| {
+        let name: String = name.to_string();
+        quote! {
+            let __final: Option<&str> = __test_history.back().map(|&state| __TEST_STATE_NAMES[state]);
+            assert_eq!(__final, Some(#name), "banish! machine ended in the wrong state after visiting {}", __trail);
+        }
+    });
+
+    proc_macro::TokenStream::from(quote! {{
+        const __TEST_STATE_NAMES: &[&str] = &[#(#names),*];
+        #(#setup)*
+        let mut __test_history: ::std::collections::VecDeque<usize> = ::std::collections::VecDeque::with_capacity(64);
+        let __result = #machine;
+        let __trail: String = __test_history.iter()
+            .map(|&state| __TEST_STATE_NAMES[state])
+            .collect::<Vec<&str>>()
+            .join(" -> ");
+        #value_check
+        #final_check
+    }})
+}
+
+// How many of the latest state entries `detect_cycles` compares a new entry against
+const CYCLE_WINDOW: usize = 64;
+
+fn expand(input: &Context) -> proc_macro2::TokenStream {
+    if let Err(err) = validate_state_and_rule_names(input) {
+        return err.to_compile_error();
+    }
+    if let Err(err) = validate_unconditional_transitions(input) {
+        return err.to_compile_error();
+    }
+    if input.options.total && let Err(err) = validate_total(input) {
+        return err.to_compile_error();
+    }
+    let mut warnings = match dead_rule_warnings(input) {
+        Ok(warnings) => warnings,
+        Err(err) => return err.to_compile_error(),
+    };
+    match reserved_name_warnings(input) {
+        Ok(reserved) => warnings.extend(reserved),
+        Err(err) => return err.to_compile_error(),
+    }
+
+    let iter: bool = input.options.iter;
+    let state_blocks = input.states.iter().enumerate().map(|(index, state)| {
+        // Resume points are numbered per state, 0 meaning the pass is not resuming after a `yield`
+        let mut next_resume: usize = 1;
+        let rules: Vec<proc_macro2::TokenStream> = state.rules.iter().map(|func| {
+            // A switch runs the first matching arm every iteration, like a set of exclusive conditioned rules.
+            if let Some(switch) = &func.switch {
+                let scrutinee: &Expr = &switch.scrutinee;
+                let rule_hook = rule_hooks(&state.name, &func.name, input);
+                let arms = switch.arms.iter().map(|arm| {
+                    let pat: &Pat = &arm.pat;
+                    let guard = arm.guard.as_ref().map(|guard| quote! { if #guard });
+                    let body = arm.body.iter().map(|stmt| generate_stmt(stmt, input));
                     quote! {
-                        if #condition {
+                        #pat #guard => {
                             __interaction = true;
+                            #rule_hook
                             #(#body)*
-                        } else {
-                            #(#else_body)*
                         }
                     }
+                });
+
+                let switch = quote! {
+                    match #scrutinee {
+                        #(#arms)*
+                        #[allow(unreachable_patterns)]
+                        _ => {}
+                    }
+                };
+                return if iter { quote! { if __resume == 0 { #switch } } } else { switch };
+            }
+
+            // If a rule has a condition, we want to run it every iteration until the condition is false.
+            // A rearmable rule runs once per state entry and again after each `rearm`.
+            // If a rule is conditionless, we want to run it only once per state.
+            let (entry, prefix) = if let Some(condition) = &func.condition {
+                (quote! { #condition }, quote! {})
+            } else if func.rearmable {
+                let armed: Ident = armed_flag(&func.name);
+                (quote! { #armed }, quote! { #armed = false; })
+            } else {
+                (quote! { __first_iteration }, quote! {})
+            };
+
+            let counter: Ident = every_counter(&func.name);
+            let reset = func.every.as_ref().map(|_| quote! { #counter = 0; });
+            let rule_hook = rule_hooks(&state.name, &func.name, input);
+
+            let mut body_resumes: Vec<usize> = Vec::new();
+            let mut body = generate_body(
+                &func.body,
+                quote! { #prefix #reset __interaction = true; #rule_hook },
+                input,
+                &mut body_resumes,
+                &mut next_resume,
+            );
+            let entry = resume_entry(entry, &body_resumes, iter);
+
+            // An `every` rule counts the passes where its condition holds and only runs its body on every Nth one.
+            // Those skipped passes still count as interactions, so the state does not settle early.
+            if let Some(every) = func.every {
+                let (count, due) = if iter {
+                    (
+                        quote! { if __resume == 0 { __interaction = true; #counter += 1; } },
+                        quote! { __resume != 0 || #counter == #every },
+                    )
                 } else {
+                    (quote! { __interaction = true; #counter += 1; }, quote! { #counter == #every })
+                };
+                body = quote! {
+                    #count
+                    if #due {
+                        #body
+                    }
+                };
+            }
+
+            match &func.else_body {
+                Some(else_block) => {
+                    let mut else_resumes: Vec<usize> = Vec::new();
+                    let else_body = generate_body(else_block, quote! {}, input, &mut else_resumes, &mut next_resume);
+                    let else_entry = iter.then(|| quote! { if __resume == 0 #(|| __resume == #else_resumes)* });
                     quote! {
-                        if #condition {
-                            __interaction = true;
-                            #(#body)*
+                        if #entry {
+                            #body
+                        } else #else_entry {
+                            #else_body
                         }
                     }
                 }
+                None => quote! {
+                    if #entry {
+                        #body
+                    }
+                },
             }
-            // If a rule is conditionless, we want to run it only once per state.
-            else {
+        }).collect();
+
+        let rule_locals = state.rules.iter().flat_map(rule_locals).map(|(local, init)| {
+            if iter { quote! { #local = #init; } } else { quote! { let mut #local = #init; } }
+        });
+
+        // Record every state entry so a misbehaving machine leaves a trail
+        let history_push = input.options.history.as_ref().map(|(_, bound)| quote! {
+            while __history.len() >= #bound {
+                __history.pop_front();
+            }
+            __history.push_back(__current_state);
+        });
+
+        // A state entered again with the same snapshot can only repeat what already happened
+        let cycle_check = input.options.detect_cycles.as_ref().map(|snapshot| {
+            let report = if input.options.catch {
                 quote! {
-                    if __first_iteration {
-                        __interaction = true;
-                        #(#body)*
+                    if __cycle_trail.contains(&__snapshot) {
+                        return ::std::result::Result::Err(::banish::BanishError::Cycle { state: __STATE_NAMES[__current_state] });
                     }
                 }
+            } else {
+                quote! {
+                    if let Some(__start) = __cycle_trail.iter().position(|&entry| entry == __snapshot) {
+                        let __cycle: Vec<&str> = __cycle_trail.range(__start..).map(|&(state, _)| __STATE_NAMES[state]).collect();
+                        panic!(
+                            "Error: Cycle detected, state '{}' re-entered with an identical snapshot: {} -> {}",
+                            __STATE_NAMES[__current_state], __cycle.join(" -> "), __STATE_NAMES[__current_state]
+                        );
+                    }
+                }
+            };
+            quote! {
+                let __snapshot: (usize, u64) = (__current_state, {
+                    use ::std::hash::{Hash, Hasher};
+                    let mut __hasher = ::std::collections::hash_map::DefaultHasher::new();
+                    (#snapshot).hash(&mut __hasher);
+                    __hasher.finish()
+                });
+                #report
+                if __cycle_trail.len() == #CYCLE_WINDOW {
+                    __cycle_trail.pop_front();
+                }
+                __cycle_trail.push_back(__snapshot);
             }
         });
 
+        // The machine handle is a snapshot rebuilt at the start of every pass
+        let state_name: String = state.name.to_string();
+        let (machine_init, machine_handle, machine_step) = if input.options.machine {
+            let tags = &state.tags;
+            (
+                Some(if iter { quote! { __iteration = 0; } } else { quote! { let mut __iteration: usize = 0; } }),
+                Some(quote! {
+                    #[allow(unused_variables)]
+                    let machine = __BanishMachine { state: #state_name, tags: &[#(#tags),*], iteration: __iteration };
+                }),
+                Some(quote! { __iteration += 1; }),
+            )
+        } else { (None, None, None) };
+
+        // Falling through the final state without a ring ends the machine instead of moving to another state
+        let falls_off: bool = !input.options.ring && index + 1 == input.states.len();
+
         // State loop
         // If no interactions occur in a full pass, exit state
         let index: syn::Index = syn::Index::from(index);
+
+        // A ring wraps fall-through from the last state back to the first
+        let next_state = if input.options.ring {
+            let count: usize = input.states.len();
+            quote! { (__current_state + 1) % #count }
+        } else {
+            quote! { __current_state + 1 }
+        };
+        let fall_through_callback = input.options.on_transition.as_ref().filter(|_| !falls_off).map(|_| quote! {
+            __on_transition(__current_state, #next_state);
+        });
+
+        // Only debug builds count passes, so release builds pay nothing for the assertion
+        let (passes_init, passes_check) = if input.options.max_passes.is_some() {
+            let message: String = format!("Error: State '{}' did not converge within {{}} passes", state.name);
+            let check = if input.options.catch {
+                quote! {
+                    if __passes > __max_passes {
+                        return ::std::result::Result::Err(
+                            ::banish::BanishError::NoConvergence { state: #state_name, passes: __max_passes }
+                        );
+                    }
+                }
+            } else {
+                quote! { assert!(__passes <= __max_passes, #message, __max_passes); }
+            };
+            (
+                Some(if iter {
+                    quote! { #[cfg(debug_assertions)] { __passes = 0; } }
+                } else {
+                    quote! { #[cfg(debug_assertions)] let mut __passes: usize = 0; }
+                }),
+                Some(quote! {
+                    #[cfg(debug_assertions)]
+                    {
+                        __passes += 1;
+                        #check
+                    }
+                }),
+            )
+        } else { (None, None) };
+
+        let coverage_enter = input.options.coverage.as_ref().map(|_| quote! {
+            __coverage.insert(#state_name);
+        });
+
+        // A state that times out counts its passes from entry and leaves once the limit is reached
+        let (timeout_init, timeout_check) = match &state.timeout {
+            Some((passes, target)) => {
+                let index: usize = input.states.iter()
+                    .position(|state| &state.name == target)
+                    .expect("timeout targets are validated before codegen");
+                let transition = generate_transition(target, index, input);
+                (
+                    Some(if iter {
+                        quote! { __timeout_passes = 0; }
+                    } else {
+                        quote! { let mut __timeout_passes: usize = 0; }
+                    }),
+                    Some(quote! {
+                        if __timeout_passes == #passes {
+                            #transition
+                        }
+                        __timeout_passes += 1;
+                    }),
+                )
+            }
+            None => (None, None),
+        };
+
+        let (metrics_enter, metrics_iter, metrics_exit) = if input.options.metrics.is_some() {
+            (
+                Some(quote! { ::banish::Metrics::on_enter(__metrics, __current_state); }),
+                Some(quote! { ::banish::Metrics::on_iter(__metrics, __current_state); }),
+                Some(quote! { ::banish::Metrics::on_exit(__metrics, __current_state); }),
+            )
+        } else { (None, None, None) };
+
+        // An excluded state keeps its index and simply falls through
+        let cfg = state.cfg.as_ref().map(|cfg| quote! {
+            #[cfg(not(#cfg))]
+            #index => {
+                #fall_through_callback
+                __current_state = #next_state;
+            }
+        });
+        let cfg_attr = state.cfg.as_ref().map(|cfg| quote! { #[cfg(#cfg)] });
+
+        // An iterator re-enters the state arm on every call, so entry setup only runs once per visit
+        let (entry, pass_start, exit) = if iter {
+            (
+                quote! {
+                    if !__entered {
+                        __entered = true;
+                        #metrics_enter
+                        #coverage_enter
+                        #history_push
+                        #cycle_check
+                        #machine_init
+                        #passes_init
+                        #timeout_init
+                        __first_iteration = true;
+                        #(#rule_locals)*
+                    }
+                },
+                quote! { if __resume == 0 { __interaction = false; #timeout_check #passes_check #metrics_iter } },
+                Some(quote! { __entered = false; }),
+            )
+        } else {
+            (
+                quote! {
+                    #metrics_enter
+                    #coverage_enter
+                    #history_push
+                    #cycle_check
+                    #machine_init
+                    #passes_init
+                    #timeout_init
+                    let mut __first_iteration = true;
+                    #(#rule_locals)*
+                },
+                quote! { __interaction = false; #timeout_check #passes_check #metrics_iter },
+                None,
+            )
+        };
+
         quote! {
+            #cfg
+            #cfg_attr
             #index => {
-                let mut __first_iteration = true;
+                #entry
                 loop {
-                    __interaction = false;
+                    #pass_start
+                    #machine_handle
                     #(#rules)*
+                    #machine_step
                     if __first_iteration { __first_iteration = false; }
                     if !__interaction {
                         break;
                     }
                 }
 
-                __current_state += 1;
+                #exit
+                #metrics_exit
+                #fall_through_callback
+                __current_state = #next_state;
             }
         }
     });
 
-    let expanded: proc_macro2::TokenStream = quote! {{
-        (move || {
+    // Option values are evaluated outside the closure so only the references are moved into it
+    let history_binding = input.options.history.as_ref().map(|(history, _)| quote! {
+        let __history: &mut ::std::collections::VecDeque<usize> = #history;
+    });
+
+    let ctx_binding = input.options.ctx.as_ref().map(|ctx| quote! {
+        let ctx: &_ = #ctx;
+    });
+
+    let coverage_binding = input.options.coverage.as_ref().map(|coverage| quote! {
+        let __coverage: &mut ::std::collections::HashSet<&'static str> = #coverage;
+    });
+
+    let machine_struct = input.options.machine.then(|| quote! {
+        #[derive(Clone, Copy)]
+        struct __BanishMachine {
+            state: &'static str,
+            tags: &'static [&'static str],
+            iteration: usize,
+        }
+
+        #[allow(dead_code)]
+        impl __BanishMachine {
+            fn state(&self) -> &'static str { self.state }
+            fn tags(&self) -> &'static [&'static str] { self.tags }
+            fn iteration(&self) -> usize { self.iteration }
+        }
+    });
+
+    // Passing the callback through a generic function pins its signature for closure inference
+    let on_transition_binding = input.options.on_transition.as_ref().map(|on_transition| quote! {
+        let mut __on_transition = {
+            fn __on_transition<F: FnMut(usize, usize)>(callback: F) -> F { callback }
+            __on_transition(#on_transition)
+        };
+    });
+
+    // The hooks are called through the trait, so the binding only pins the type to a `Metrics` implementor
+    let metrics_binding = input.options.metrics.as_ref().map(|metrics| quote! {
+        let __metrics_target = {
+            fn __metrics<M: ::banish::Metrics + ?Sized>(metrics: &mut M) -> &mut M { metrics }
+            __metrics(#metrics)
+        };
+    });
+    let metrics_return = input.options.metrics.as_ref().map(|_| quote! {
+        ::banish::Metrics::on_return(__metrics_target);
+    });
+
+    let max_passes_binding = input.options.max_passes.as_ref().map(|max_passes| quote! {
+        #[cfg(debug_assertions)]
+        let __max_passes: usize = #max_passes;
+    });
+
+    let cycle_tracking = input.options.detect_cycles.as_ref().map(|_| {
+        let names = input.states.iter().map(|state| state.name.to_string());
+        quote! {
+            const __STATE_NAMES: &[&str] = &[#(#names),*];
+            let mut __cycle_trail: ::std::collections::VecDeque<(usize, u64)> =
+                ::std::collections::VecDeque::with_capacity(#CYCLE_WINDOW);
+        }
+    });
+
+    // Option values are bound once, before the machine, in every kind of expansion
+    let bindings = quote! {
+        #history_binding
+        #ctx_binding
+        #coverage_binding
+        #on_transition_binding
+        #max_passes_binding
+        #metrics_binding
+    };
+
+    let expanded: proc_macro2::TokenStream = if iter {
+        // Everything that must survive between calls to `next` lives outside the iterator's closure
+        // Rules with the same name in different states share their locals, which are reset on entry anyway
+        let mut local_names: HashSet<String> = HashSet::new();
+        let rule_locals = input.states.iter()
+            .flat_map(|state| state.rules.iter().flat_map(rule_locals))
+            .filter(|(local, _)| local_names.insert(local.to_string()))
+            .map(|(local, init)| quote! { let mut #local = #init; });
+        let machine_iteration = input.options.machine.then(|| quote! { let mut __iteration: usize = 0; });
+        let timeout_passes = input.states.iter().any(|state| state.timeout.is_some()).then(|| quote! {
+            let mut __timeout_passes: usize = 0;
+        });
+        let passes = input.options.max_passes.as_ref().map(|_| quote! {
+            #[cfg(debug_assertions)]
+            let mut __passes: usize = 0;
+        });
+        let item_type = input.options.return_type.as_ref().map(|item_type| quote! { -> Option<#item_type> });
+        let machine = quote! {
+            'banish_main: loop {
+                match __current_state {
+                    #(#state_blocks)*
+                    _ => {
+                        return None;
+                    },
+                }
+            }
+        };
+
+        // `on_return` needs `return None;` and falling through the final state to land in one place,
+        // so with metrics each call runs the machine in a closure
+        let next = match &metrics_return {
+            Some(metrics_return) => quote! {
+                let __item = {
+                    let __metrics = &mut *__metrics_target;
+                    (|| #item_type { #machine })()
+                };
+                if __item.is_none() {
+                    #metrics_return
+                }
+                __item
+            },
+            None => machine,
+        };
+
+        quote! {{
+            #bindings
+            let mut __current_state: usize = 0;
+            let mut __interaction: bool = false;
+            let mut __first_iteration: bool = true;
+            let mut __entered: bool = false;
+            let mut __resume: usize = 0;
+            #cycle_tracking
+            #machine_iteration
+            #passes
+            #timeout_passes
+            #(#rule_locals)*
+            ::std::iter::from_fn(move || #item_type {
+                #machine_struct
+                #next
+            }).fuse()
+        }}
+    } else {
+        let no_return = if input.options.catch {
+            quote! { return ::std::result::Result::Err(::banish::BanishError::NoReturn); }
+        } else {
+            quote! { panic!("Error: No return in final state"); }
+        };
+        let machine = quote! {
+            #machine_struct
             let mut __current_state: usize = 0;
             let mut __interaction: bool = false;
+            #cycle_tracking
             'banish_main: loop {
                 match __current_state {
                     #(#state_blocks)*
                     _ => {
-                        panic!("Error: No return in final state");
+                        #no_return
                     },
                 }
             }
-        })()
-    }};
-    proc_macro::TokenStream::from(expanded)
+        };
+
+        // Without an annotation, a machine whose returns carry no value is explicitly unit-returning.
+        // One without any `return` never produces a value, so outside an impl it is left to inference.
+        let unit: bool = !returns_value(input) && (has_return(input) || input.options.impl_for.is_some());
+        let return_type: Option<proc_macro2::TokenStream> = match &input.options.return_type {
+            Some(return_type) => Some(quote! { #return_type }),
+            None if unit => Some(quote! { () }),
+            None => None,
+        };
+        // Checked before the `catch` wrapping, which would otherwise leave a `_` in the Output type
+        if let Some((trait_path, _)) = &input.options.impl_for
+            && return_type.is_none()
+        {
+            let message: &str = "Returning a value from an 'impl' machine requires '-> T' for the Output type";
+            return syn::Error::new(trait_path.span(), message).to_compile_error();
+        }
+        // A catching machine reports falling off its final state as an error, so every return value is wrapped in `Ok`
+        let return_type: Option<proc_macro2::TokenStream> = if input.options.catch {
+            let value = return_type.unwrap_or_else(|| quote! { _ });
+            Some(quote! { ::std::result::Result<#value, ::banish::BanishError> })
+        } else {
+            return_type
+        };
+
+        // `on_return` needs every `return` to land in one place, which is the call of the machine's closure.
+        // Only a reborrow of the metrics moves into the closure, so `on_return` can use them afterwards.
+        let run = |call: proc_macro2::TokenStream| match &metrics_return {
+            Some(metrics_return) => quote! {
+                let __result = {
+                    let __metrics = &mut *__metrics_target;
+                    #call
+                };
+                #metrics_return
+                __result
+            },
+            None => call,
+        };
+
+        // As a trait implementation the machine is the body of `run`, so `return` leaves the method
+        // and rule bodies reach the fields through `self`.
+        if let Some((trait_path, self_ty)) = &input.options.impl_for {
+            let output = return_type.expect("impl machines are checked for an Output type above");
+            let run = run(quote! { (|| -> Self::Output { #machine })() });
+
+            quote! {
+                impl #trait_path for #self_ty {
+                    type Output = #output;
+
+                    fn run(&mut self) -> Self::Output {
+                        #bindings
+                        #run
+                    }
+                }
+            }
+        } else {
+            let return_type = return_type.map(|return_type| quote! { -> #return_type });
+            let run = run(quote! {
+                (move || #return_type {
+                    #machine
+                })()
+            });
+
+            quote! {{
+                #bindings
+                #run
+            }}
+        }
+    };
+
+    // The warnings are items, so they sit next to an impl or at the start of the machine's block
+    if warnings.is_empty() {
+        expanded
+    } else if input.options.impl_for.is_some() {
+        quote! { #warnings #expanded }
+    } else {
+        quote! {{ #warnings #expanded }}
+    }
+}
+
+fn parse_states(input: ParseStream) -> Result<(Vec<State>, Vec<State>)> {
+    let mut states: Vec<State> = Vec::with_capacity(2);
+    while !input.is_empty() {
+        states.push(input.parse()?);
+    }
+
+    resolve_inheritance(states)
+}
+
+// Switches are named in order of appearance within their state
+fn name_switches(rules: &mut [Rule]) {
+    for (index, rule) in rules.iter_mut().filter(|rule| rule.switch.is_some()).enumerate() {
+        rule.name = format_ident!("switch_{}", index, span = rule.name.span());
+    }
+}
+
+// Prepends the rules of each base state to the states inheriting from it.
+// Abstract states are never entered, so they are split off and take no index, but are still validated.
+fn resolve_inheritance(mut states: Vec<State>) -> Result<(Vec<State>, Vec<State>)> {
+    let mut resolved: Vec<bool> = vec![false; states.len()];
+    for index in 0..states.len() {
+        resolve_state(&mut states, index, &mut resolved, &mut Vec::new())?;
+    }
+
+    for state in &states {
+        if let Some((_, target)) = &state.timeout
+            && states.iter().any(|state| state.is_abstract && &state.name == target)
+        {
+            return Err(syn::Error::new(
+                target.span(),
+                format!("Cannot time out into abstract state '{}'", target),
+            ));
+        }
+
+        for stmt in state.rules.iter().flat_map(rule_stmts) {
+            let BanishStmt::StateTransition(primary, fallback, _) = stmt else { continue };
+            for target in std::iter::once(primary).chain(fallback) {
+                if states.iter().any(|state| state.is_abstract && &state.name == target) {
+                    return Err(syn::Error::new(
+                        target.span(),
+                        format!("Cannot transition to abstract state '{}'", target),
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(states.into_iter().partition(|state| !state.is_abstract))
+}
+
+fn resolve_state(states: &mut [State], index: usize, resolved: &mut [bool], visiting: &mut Vec<usize>) -> Result<()> {
+    if resolved[index] { return Ok(()); }
+    let Some(base) = states[index].base.clone() else {
+        resolved[index] = true;
+        return Ok(());
+    };
+
+    if visiting.contains(&index) {
+        return Err(syn::Error::new(
+            base.span(),
+            format!("Inheritance cycle through state '{}'", states[index].name),
+        ));
+    }
+    let base_index: usize = states.iter().position(|state| state.name == base).ok_or_else(|| {
+        syn::Error::new(base.span(), format!("Unknown base state '{}'", base))
+    })?;
+
+    visiting.push(index);
+    resolve_state(states, base_index, resolved, visiting)?;
+    visiting.pop();
+
+    let mut rules: Vec<Rule> = states[base_index].rules.clone();
+    let state: &mut State = &mut states[index];
+    for rule in state.rules.iter().filter(|rule| rule.switch.is_none()) {
+        if rules.iter().any(|inherited| inherited.name == rule.name) {
+            return Err(syn::Error::new(
+                rule.name.span(),
+                format!(
+                    "Rule '{}' in state '{}' collides with a rule inherited from '{}'",
+                    rule.name, state.name, base
+                ),
+            ));
+        }
+    }
+
+    rules.append(&mut state.rules);
+    name_switches(&mut rules);
+    state.rules = rules;
+    resolved[index] = true;
+
+    Ok(())
+}
+
+// Parses an expression that is directly followed by a block, such as a switch scrutinee.
+fn parse_expr_until_brace(input: ParseStream) -> Result<Expr> {
+    let expr_tokens: proc_macro2::TokenStream = collect_until_brace(input)?;
+
+    // Now parse those isolated tokens as an Expression.
+    // Since the '{' isn't in 'expr_tokens', syn can't mistake it for a struct!
+    syn::parse2(expr_tokens)
+}
+
+fn collect_until_brace(input: ParseStream) -> Result<proc_macro2::TokenStream> {
+    let mut expr_tokens = proc_macro2::TokenStream::new();
+
+    // Loop until we see the start of the body block
+    while !input.peek(syn::token::Brace) {
+        if input.is_empty() {
+            return Err(input.error("Unexpected end of input, expected body '{'"));
+        }
+        // Pull one token at a time (e.g., "buffer", "[", "idx", "]", "==", "target")
+        expr_tokens.extend(std::iter::once(input.parse::<TokenTree>()?));
+    }
+
+    Ok(expr_tokens)
+}
+
+// Reads the count of `every`, `timeout` or `history`. Generated code compares it against a `usize`,
+// so the parsed value is used and a suffix on the literal does not matter.
+fn parse_positive_count(lit: &syn::LitInt, what: &str) -> Result<usize> {
+    let count: usize = lit.base10_parse()?;
+    if count == 0 {
+        return Err(syn::Error::new(lit.span(), format!("'{}' needs a count above zero", what)));
+    }
+
+    Ok(count)
 }
 
 fn parse_rule_block(content: &syn::parse::ParseBuffer) -> Result<Vec<BanishStmt>> {
@@ -210,8 +1199,41 @@ fn parse_rule_block(content: &syn::parse::ParseBuffer) -> Result<Vec<BanishStmt>
             content.parse::<Token![=>]>()?;
             content.parse::<Token![@]>()?;
             let state: Ident = content.parse()?;
+            let fallback: Option<Ident> = if content.peek(Token![else]) {
+                content.parse::<Token![else]>()?;
+                content.parse::<Token![@]>()?;
+                Some(content.parse()?)
+            } else { None };
+            let guard: Option<Expr> = if content.peek(Token![if]) {
+                content.parse::<Token![if]>()?;
+                Some(content.parse()?)
+            } else { None };
+            content.parse::<Token![;]>()?;
+            body.push(BanishStmt::StateTransition(state, fallback, guard));
+        }
+        else if content.peek(Token![yield]) {
+            content.parse::<Token![yield]>()?;
+            let value: Expr = content.parse()?;
+            content.parse::<Token![;]>()?;
+            body.push(BanishStmt::Yield(value));
+        }
+        else if is_rearm(content) {
+            content.parse::<Ident>()?;
+            let rule: Ident = content.parse()?;
             content.parse::<Token![;]>()?;
-            body.push(BanishStmt::StateTransition(state));
+            body.push(BanishStmt::Rearm(rule));
+        }
+        // `machine.transition_to(@state);` is method-style sugar for `=> @state;`
+        else if is_machine_transition(content) {
+            content.parse::<Ident>()?;
+            content.parse::<Token![.]>()?;
+            content.parse::<Ident>()?;
+            let args: syn::parse::ParseBuffer<'_>;
+            parenthesized!(args in content);
+            args.parse::<Token![@]>()?;
+            let state: Ident = args.parse()?;
+            content.parse::<Token![;]>()?;
+            body.push(BanishStmt::StateTransition(state, None, None));
         }
         else {
             let stmt: Stmt = content.parse()?;
@@ -222,27 +1244,204 @@ fn parse_rule_block(content: &syn::parse::ParseBuffer) -> Result<Vec<BanishStmt>
     Ok(body)
 }
 
+fn is_timeout(input: ParseStream) -> bool {
+    let fork = input.fork();
+    matches!(fork.parse::<Ident>(), Ok(ident) if ident == "timeout") && fork.peek(syn::token::Paren)
+}
+
+fn is_rearm(content: &syn::parse::ParseBuffer) -> bool {
+    let fork = content.fork();
+    matches!(fork.parse::<Ident>(), Ok(ident) if ident == "rearm")
+        && fork.parse::<Ident>().is_ok()
+        && fork.peek(Token![;])
+}
+
+fn armed_flag(rule: &Ident) -> Ident {
+    format_ident!("{}{}", ARMED_PREFIX, rule)
+}
+
+// Calls made whenever a rule body or switch arm runs
+fn rule_hooks(state: &Ident, rule: &Ident, input: &Context) -> proc_macro2::TokenStream {
+    let name: String = rule.to_string();
+    let metrics = input.options.metrics.as_ref().map(|_| quote! {
+        ::banish::Metrics::on_rule(__metrics, __current_state, #name);
+    });
+    let covered: String = format!("{}.{}", state, rule);
+    let coverage = input.options.coverage.as_ref().map(|_| quote! {
+        __coverage.insert(#covered);
+    });
+
+    quote! { #metrics #coverage }
+}
+
+fn every_counter(rule: &Ident) -> Ident {
+    format_ident!("{}{}", EVERY_PREFIX, rule)
+}
+
+// Per-rule locals with their initial values, reset on every state entry
+fn rule_locals(rule: &Rule) -> Vec<(Ident, proc_macro2::TokenStream)> {
+    let mut locals: Vec<(Ident, proc_macro2::TokenStream)> = Vec::new();
+    if rule.rearmable {
+        locals.push((armed_flag(&rule.name), quote! { true }));
+    }
+    if rule.every.is_some() {
+        locals.push((every_counter(&rule.name), quote! { 0usize }));
+    }
+
+    locals
+}
+
+fn is_machine_transition(content: &syn::parse::ParseBuffer) -> bool {
+    let fork = content.fork();
+    matches!(fork.parse::<Ident>(), Ok(ident) if ident == "machine")
+        && fork.parse::<Token![.]>().is_ok()
+        && matches!(fork.parse::<Ident>(), Ok(ident) if ident == "transition_to")
+        && fork.peek(syn::token::Paren)
+}
+
 fn generate_stmt(stmt: &BanishStmt, input: &Context) -> proc_macro2::TokenStream {
     match stmt {
+        BanishStmt::Rust(stmt) if input.options.catch => {
+            let mut stmt: Stmt = stmt.clone();
+            WrapReturns.visit_stmt_mut(&mut stmt);
+            quote! { #stmt }
+        }
         BanishStmt::Rust(stmt) => quote! { #stmt },
-        BanishStmt::StateTransition(transition) => {
-            let target: usize = input.states
-                .iter()
-                .position(|state| &state.name == transition)
-                .unwrap_or_else(|| { panic!("Error: Invalid state transition target {}", transition); });
-            
-            let target: syn::Index = syn::Index::from(target);
-            quote! {
-                __current_state = #target;
-                continue 'banish_main;
+        BanishStmt::StateTransition(primary, fallback, guard) => {
+            let position = |name: &Ident| input.states.iter().position(|state| &state.name == name);
+            let fallback: Option<(&Ident, usize)> =
+                fallback.as_ref().and_then(|fallback| Some((fallback, position(fallback)?)));
+
+            // The fallback is only taken when the primary target is missing or compiled out
+            let transition = match position(primary) {
+                Some(target) => match (&input.states[target].cfg, fallback) {
+                    (Some(cfg), Some((fallback, fallback_target))) => {
+                        let primary = generate_transition(primary, target, input);
+                        let fallback = generate_transition(fallback, fallback_target, input);
+                        quote! {
+                            #[cfg(#cfg)]
+                            { #primary }
+                            #[cfg(not(#cfg))]
+                            { #fallback }
+                        }
+                    }
+                    _ => generate_transition(primary, target, input),
+                },
+                None => {
+                    let (fallback, target) = fallback.expect("transition targets are validated before codegen");
+                    generate_transition(fallback, target, input)
+                }
+            };
+
+            // A guarded transition falls back to the rest of the body when its guard is false
+            match guard {
+                Some(guard) => quote! {
+                    if #guard {
+                        #transition
+                    }
+                },
+                None => transition,
             }
         }
+        BanishStmt::Rearm(rule) => {
+            let armed: Ident = armed_flag(rule);
+            quote! { #armed = true; }
+        }
+        BanishStmt::Yield(_) => unreachable!("yield is split out by generate_body"),
+    }
+}
+
+fn generate_transition(name: &Ident, target: usize, input: &Context) -> proc_macro2::TokenStream {
+    // A state that is compiled out cannot be transitioned to
+    let excluded = input.states[target].cfg.as_ref().map(|cfg| {
+        let message: String = format!("Transition to state '{}', which is excluded by its cfg", name);
+        quote_spanned! { name.span() =>
+            #[cfg(not(#cfg))]
+            compile_error!(#message);
+        }
+    });
+
+    let target: syn::Index = syn::Index::from(target);
+    let callback = input.options.on_transition.as_ref().map(|_| quote! {
+        __on_transition(__current_state, #target);
+    });
+    let metrics_exit = input.options.metrics.as_ref().map(|_| quote! {
+        ::banish::Metrics::on_exit(__metrics, __current_state);
+    });
+    // An iterator has to run the entry setup of the target state again
+    let reenter = input.options.iter.then(|| quote! { __entered = false; });
+    quote! {
+        #excluded
+        #metrics_exit
+        #callback
+        #reenter
+        __current_state = #target;
+        continue 'banish_main;
     }
 }
 
+// Generates a rule body. In an iterator, each `yield` splits the body so the next call resumes right after it.
+fn generate_body(
+    stmts: &[BanishStmt],
+    prefix: proc_macro2::TokenStream,
+    input: &Context,
+    resumes: &mut Vec<usize>,
+    next_resume: &mut usize,
+) -> proc_macro2::TokenStream {
+    if !stmts.iter().any(|stmt| matches!(stmt, BanishStmt::Yield(_))) {
+        let stmts = stmts.iter().map(|stmt| generate_stmt(stmt, input));
+        return quote! { #prefix #(#stmts)* };
+    }
+
+    let mut body = proc_macro2::TokenStream::new();
+    let mut segment: proc_macro2::TokenStream = prefix;
+    for stmt in stmts {
+        if let BanishStmt::Yield(value) = stmt {
+            let resume: usize = *next_resume;
+            *next_resume += 1;
+            resumes.push(resume);
+
+            body.extend(quote! {
+                if __resume == 0 {
+                    #segment
+                    __resume = #resume;
+                    return Some(#value);
+                }
+                if __resume == #resume { __resume = 0; }
+            });
+            segment = proc_macro2::TokenStream::new();
+        }
+        else {
+            segment.extend(generate_stmt(stmt, input));
+        }
+    }
+    body.extend(quote! { if __resume == 0 { #segment } });
+
+    body
+}
+
+// In an iterator a rule is entered normally when not resuming, or unconditionally when resuming inside it.
+fn resume_entry(entry: proc_macro2::TokenStream, resumes: &[usize], iter: bool) -> proc_macro2::TokenStream {
+    if iter {
+        quote! { (__resume == 0 && (#entry)) #(|| __resume == #resumes)* }
+    } else {
+        entry
+    }
+}
+
+fn rule_stmts(rule: &Rule) -> impl Iterator<Item = &BanishStmt> {
+    let arms = rule.switch.iter().flat_map(|switch| switch.arms.iter().flat_map(|arm| &arm.body));
+    rule.body.iter().chain(rule.else_body.iter().flatten()).chain(arms)
+}
+
+// Abstract states are never entered, but their rules are checked like any other
+fn all_states(input: &Context) -> impl Iterator<Item = &State> {
+    input.states.iter().chain(&input.abstract_states)
+}
+
 fn validate_state_and_rule_names(input: &Context) -> syn::Result<()> {
     let mut state_names: HashSet<String> = HashSet::new();
-    for state in &input.states {
+    for state in all_states(input) {
         let name: String = state.name.to_string();
         if !state_names.insert(name.clone()) {
             return Err(syn::Error::new(
@@ -251,6 +1450,16 @@ fn validate_state_and_rule_names(input: &Context) -> syn::Result<()> {
             ));
         }
 
+        let mut tags: HashSet<String> = HashSet::new();
+        for tag in &state.tags {
+            if !tags.insert(tag.value()) {
+                return Err(syn::Error::new(
+                    tag.span(),
+                    format!("Duplicate tag '{}' on state '{}'", tag.value(), state.name),
+                ));
+            }
+        }
+
         let mut rule_names: HashSet<String> = HashSet::new();
         for rule in &state.rules {
             let name: String = rule.name.to_string();
@@ -265,7 +1474,457 @@ fn validate_state_and_rule_names(input: &Context) -> syn::Result<()> {
                 ));
             }
         }
+
+        // Yields are only resumable at the top level of a rule body or else clause of an iterator
+        for rule in &state.rules {
+            let arms = rule.switch.iter().flat_map(|switch| switch.arms.iter().flat_map(|arm| &arm.body));
+            if let Some(BanishStmt::Yield(value)) = arms.clone().find(|stmt| matches!(stmt, BanishStmt::Yield(_))) {
+                return Err(syn::Error::new_spanned(value, "'yield' is not supported inside switch arms"));
+            }
+            if !input.options.iter
+                && let Some(BanishStmt::Yield(value)) = rule_stmts(rule).find(|stmt| matches!(stmt, BanishStmt::Yield(_)))
+            {
+                return Err(syn::Error::new_spanned(value, "'yield' requires the 'iter' option"));
+            }
+        }
+
+        // Only rearmable rules of the same state can be rearmed
+        for stmt in state.rules.iter().flat_map(rule_stmts) {
+            if let BanishStmt::Rearm(target) = stmt
+                && !state.rules.iter().any(|rule| rule.rearmable && &rule.name == target)
+            {
+                return Err(syn::Error::new(
+                    target.span(),
+                    format!(
+                        "Cannot rearm '{}', expected a '?!' rule in state '{}'",
+                        target, state.name
+                    ),
+                ));
+            }
+        }
+    }
+
+    for (_, target) in input.states.iter().filter_map(|state| state.timeout.as_ref()) {
+        if !state_names.contains(&target.to_string()) {
+            return Err(syn::Error::new(
+                target.span(),
+                format!("Invalid timeout target '{}'", target),
+            ));
+        }
+    }
+
+    // Transition targets may be declared before or after the state transitioning to them
+    for stmt in all_states(input).flat_map(|state| state.rules.iter().flat_map(rule_stmts)) {
+        // A fallback only has to exist when the primary target does not
+        if let BanishStmt::StateTransition(target, fallback, _) = stmt
+            && !state_names.contains(&target.to_string())
+            && !fallback.as_ref().is_some_and(|fallback| state_names.contains(&fallback.to_string()))
+        {
+            return Err(syn::Error::new(
+                target.span(),
+                format!("Invalid state transition target '{}'", target),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Every name that banish! or banish_test! declares where rule code can see it, grouped by where it is generated.
+// Any new binding that rule code can reach belongs here, so the reserved name warnings stay complete.
+const RESERVED_NAMES: &[&str] = &[
+    // The state loop of every machine
+    "__current_state", "__interaction", "__first_iteration", "__timeout_passes",
+    // Iterators
+    "__entered", "__resume",
+    // Options
+    "__history", "__on_transition", "__metrics", "__metrics_target", "__coverage", "__passes", "__max_passes",
+    "__cycle_trail", "__snapshot", "__STATE_NAMES", "__iteration", "__BanishMachine",
+    // banish_test!
+    "__test_history", "__TEST_STATE_NAMES",
+];
+const ARMED_PREFIX: &str = "__armed_";
+const EVERY_PREFIX: &str = "__every_";
+const RESERVED_PREFIXES: &[&str] = &[ARMED_PREFIX, EVERY_PREFIX];
+
+// Rule code that touches the machine's own variables would silently corrupt it, so each reserved name is warned about once.
+fn reserved_name_warnings(input: &Context) -> syn::Result<proc_macro2::TokenStream> {
+    let mut tokens = proc_macro2::TokenStream::new();
+    for rule in all_states(input).flat_map(|state| &state.rules) {
+        rule.condition.to_tokens(&mut tokens);
+        if let Some(switch) = &rule.switch {
+            switch.scrutinee.to_tokens(&mut tokens);
+            for arm in &switch.arms {
+                arm.pat.to_tokens(&mut tokens);
+                arm.guard.to_tokens(&mut tokens);
+            }
+        }
+        for stmt in rule_stmts(rule) {
+            match stmt {
+                BanishStmt::Rust(stmt) => stmt.to_tokens(&mut tokens),
+                BanishStmt::StateTransition(_, _, guard) => guard.to_tokens(&mut tokens),
+                BanishStmt::Yield(value) => value.to_tokens(&mut tokens),
+                BanishStmt::Rearm(_) => {}
+            }
+        }
+    }
+
+    let mut found: Vec<Ident> = Vec::new();
+    find_reserved_names(tokens, &mut found);
+
+    let mut warnings = proc_macro2::TokenStream::new();
+    let mut warned: HashSet<String> = HashSet::new();
+    for ident in found {
+        if !warned.insert(ident.to_string()) { continue; }
+
+        let message: String = format!("'{}' is reserved for the variables banish generates, use another name", ident);
+        if input.options.strict {
+            return Err(syn::Error::new(ident.span(), message));
+        }
+        warnings.extend(deprecation_warning(format_ident!("__banish_reserved_name"), &message, ident.span()));
+    }
+
+    Ok(warnings)
+}
+
+fn find_reserved_names(tokens: proc_macro2::TokenStream, found: &mut Vec<Ident>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                let name: String = ident.to_string();
+                if RESERVED_NAMES.contains(&name.as_str())
+                    || RESERVED_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+                {
+                    found.push(ident);
+                }
+            }
+            TokenTree::Group(group) => find_reserved_names(group.stream(), found),
+            _ => {}
+        }
+    }
+}
+
+// Stable proc macros cannot emit warnings directly, so a warning is the use of a deprecated constant at the span.
+fn deprecation_warning(name: Ident, message: &str, span: proc_macro2::Span) -> proc_macro2::TokenStream {
+    let mut usage: Ident = name.clone();
+    usage.set_span(span);
+    quote! {
+        const _: () = {
+            #[deprecated(note = #message)]
+            const #name: () = ();
+            #usage
+        };
+    }
+}
+
+// A rule whose condition is always false never runs, which is most likely a mistake.
+fn dead_rule_warnings(input: &Context) -> syn::Result<proc_macro2::TokenStream> {
+    let mut warnings = proc_macro2::TokenStream::new();
+    for state in all_states(input) {
+        for rule in &state.rules {
+            let Some(condition) = &rule.condition else { continue };
+            if const_condition(condition) != Some(false) { continue; }
+
+            let message: String = format!(
+                "Rule '{}' in state '{}' can never run, because its condition is always false",
+                rule.name, state.name
+            );
+            if input.options.strict {
+                return Err(syn::Error::new_spanned(condition, message));
+            }
+            warnings.extend(deprecation_warning(format_ident!("__banish_dead_rule"), &message, condition.span()));
+        }
+    }
+
+    Ok(warnings)
+}
+
+enum ConstValue {
+    Bool(bool),
+    Int(i128),
+}
+
+// Evaluates a condition made only of literals, such as `false` or `1 == 2`.
+// Anything involving a variable or a call is left alone.
+fn const_condition(condition: &Expr) -> Option<bool> {
+    match const_eval(condition)? {
+        ConstValue::Bool(value) => Some(value),
+        ConstValue::Int(_) => None,
+    }
+}
+
+fn const_eval(expr: &Expr) -> Option<ConstValue> {
+    use syn::{BinOp, UnOp};
+    use ConstValue::{Bool, Int};
+
+    match expr {
+        Expr::Lit(lit) => match &lit.lit {
+            syn::Lit::Bool(value) => Some(Bool(value.value)),
+            syn::Lit::Int(value) => value.base10_parse().ok().map(Int),
+            _ => None,
+        },
+        Expr::Paren(paren) => const_eval(&paren.expr),
+        Expr::Group(group) => const_eval(&group.expr),
+        Expr::Unary(unary) => match (&unary.op, const_eval(&unary.expr)?) {
+            (UnOp::Not(_), Bool(value)) => Some(Bool(!value)),
+            (UnOp::Neg(_), Int(value)) => Some(Int(value.checked_neg()?)),
+            _ => None,
+        },
+        Expr::Binary(binary) => {
+            let (left, right) = (const_eval(&binary.left)?, const_eval(&binary.right)?);
+            match (&binary.op, left, right) {
+                (BinOp::And(_), Bool(left), Bool(right)) => Some(Bool(left && right)),
+                (BinOp::Or(_), Bool(left), Bool(right)) => Some(Bool(left || right)),
+                (BinOp::Eq(_), Bool(left), Bool(right)) => Some(Bool(left == right)),
+                (BinOp::Ne(_), Bool(left), Bool(right)) => Some(Bool(left != right)),
+                (BinOp::Eq(_), Int(left), Int(right)) => Some(Bool(left == right)),
+                (BinOp::Ne(_), Int(left), Int(right)) => Some(Bool(left != right)),
+                (BinOp::Lt(_), Int(left), Int(right)) => Some(Bool(left < right)),
+                (BinOp::Le(_), Int(left), Int(right)) => Some(Bool(left <= right)),
+                (BinOp::Gt(_), Int(left), Int(right)) => Some(Bool(left > right)),
+                (BinOp::Ge(_), Int(left), Int(right)) => Some(Bool(left >= right)),
+                (BinOp::Add(_), Int(left), Int(right)) => Some(Int(left.checked_add(right)?)),
+                (BinOp::Sub(_), Int(left), Int(right)) => Some(Int(left.checked_sub(right)?)),
+                (BinOp::Mul(_), Int(left), Int(right)) => Some(Int(left.checked_mul(right)?)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// A rule that always runs on entry and always transitions makes any later such rule in the state dead.
+fn validate_unconditional_transitions(input: &Context) -> syn::Result<()> {
+    for state in all_states(input) {
+        let mut unconditional = state.rules.iter().filter(|rule| {
+            let always_runs: bool = match &rule.condition {
+                None => rule.switch.is_none(),
+                Some(condition) => const_condition(condition) == Some(true),
+            };
+            always_runs && rule.body.iter().any(|stmt| matches!(stmt, BanishStmt::StateTransition(_, _, None)))
+        });
+
+        if let (Some(first), Some(shadowed)) = (unconditional.next(), unconditional.next()) {
+            let mut err: syn::Error = syn::Error::new(
+                shadowed.name.span(),
+                format!(
+                    "Rule '{}' in state '{}' never transitions, because rule '{}' always transitions first",
+                    shadowed.name, state.name, first.name
+                ),
+            );
+            err.combine(syn::Error::new(
+                first.name.span(),
+                format!("Rule '{}' always transitions here", first.name),
+            ));
+            return Err(err);
+        }
     }
 
     Ok(())
+}
+
+// A total machine needs every state to have a path, through transitions or fall-through, to a state that returns.
+// This is structural only, so a condition that never becomes true can still loop forever.
+fn validate_total(input: &Context) -> syn::Result<()> {
+    let count: usize = input.states.len();
+    let mut exits: Vec<bool> = vec![false; count];
+    let mut edges: Vec<Vec<usize>> = vec![Vec::new(); count];
+
+    for (index, state) in input.states.iter().enumerate() {
+        for stmt in state.rules.iter().flat_map(rule_stmts) {
+            match stmt {
+                BanishStmt::Rust(stmt) => {
+                    if contains_return(stmt) {
+                        exits[index] = true;
+                    }
+                }
+                BanishStmt::StateTransition(target, fallback, _) => {
+                    for target in std::iter::once(target).chain(fallback) {
+                        if let Some(target) = input.states.iter().position(|state| &state.name == target) {
+                            edges[index].push(target);
+                        }
+                    }
+                }
+                BanishStmt::Rearm(_) | BanishStmt::Yield(_) => {}
+            }
+        }
+
+        if let Some((_, target)) = &state.timeout
+            && let Some(target) = input.states.iter().position(|state| &state.name == target)
+        {
+            edges[index].push(target);
+        }
+
+        // A conditionless rule that transitions without a guard runs on entry, so the state can never fall through.
+        // Falling through from the last state ends the machine without a return, unless a ring wraps it to the first.
+        let always_transitions: bool = state.rules.iter().any(|rule| {
+            rule.condition.is_none()
+                && rule.body.iter().any(|stmt| matches!(stmt, BanishStmt::StateTransition(_, _, None)))
+        });
+        if !always_transitions && (index + 1 < count || input.options.ring) {
+            edges[index].push((index + 1) % count);
+        }
+    }
+
+    // Spread exits backwards until no more states change
+    let mut changed: bool = true;
+    while changed {
+        changed = false;
+        for index in 0..count {
+            if !exits[index] && edges[index].iter().any(|&target| exits[target]) {
+                exits[index] = true;
+                changed = true;
+            }
+        }
+    }
+
+    let mut errors: Vec<syn::Error> = Vec::new();
+    for (state, exits) in input.states.iter().zip(exits) {
+        if exits { continue; }
+
+        errors.push(syn::Error::new(
+            state.name.span(),
+            format!("State '{}' has no path to a 'return' in a total machine", state.name),
+        ));
+    }
+
+    // A return is no use if the states before it always move on first, so forced moves must not loop back
+    let forced: Vec<Option<usize>> = (0..count).map(|index| forced_successor(input, index)).collect();
+    let mut in_cycle: Vec<bool> = vec![false; count];
+    for start in 0..count {
+        let mut path: Vec<usize> = Vec::new();
+        let mut current: Option<usize> = Some(start);
+        while let Some(index) = current {
+            if in_cycle[index] { break; }
+            if let Some(begin) = path.iter().position(|&visited| visited == index) {
+                let cycle: Vec<String> = path[begin..].iter()
+                    .chain(std::iter::once(&index))
+                    .map(|&state| input.states[state].name.to_string())
+                    .collect();
+                errors.push(syn::Error::new(
+                    input.states[index].name.span(),
+                    format!(
+                        "States {} always transition or fall through without returning, so a total machine loops forever",
+                        cycle.iter().map(|name| format!("'{}'", name)).collect::<Vec<String>>().join(" -> ")
+                    ),
+                ));
+                for &state in &path[begin..] {
+                    in_cycle[state] = true;
+                }
+                break;
+            }
+            path.push(index);
+            current = forced[index];
+        }
+    }
+
+    errors.into_iter().reduce(|mut errors, err| {
+        errors.combine(err);
+        errors
+    }).map_or(Ok(()), Err)
+}
+
+// The state that a state always moves on to without a chance to return. That is the target of a conditionless rule
+// that transitions before any rule can return or branch, or the next state when no rule can return or transition.
+fn forced_successor(input: &Context, index: usize) -> Option<usize> {
+    let state: &State = &input.states[index];
+    let position = |name: &Ident| input.states.iter().position(|state| &state.name == name);
+
+    for rule in &state.rules {
+        let leaves: bool = rule_stmts(rule).any(|stmt| match stmt {
+            BanishStmt::Rust(stmt) => contains_return(stmt),
+            BanishStmt::StateTransition(..) => true,
+            BanishStmt::Rearm(_) | BanishStmt::Yield(_) => false,
+        });
+        if !leaves { continue; }
+        if rule.condition.is_some() || rule.switch.is_some() { return None; }
+
+        for stmt in &rule.body {
+            match stmt {
+                BanishStmt::Rust(stmt) if contains_return(stmt) => return None,
+                BanishStmt::StateTransition(_, _, Some(_)) => return None,
+                BanishStmt::StateTransition(target, fallback, None) => {
+                    return position(target).or_else(|| fallback.as_ref().and_then(position));
+                }
+                _ => {}
+            }
+        }
+        return None;
+    }
+
+    // A timeout gives a state that would otherwise only fall through a second way out
+    let count: usize = input.states.len();
+    (state.timeout.is_none() && (index + 1 < count || input.options.ring)).then_some((index + 1) % count)
+}
+
+fn has_return(input: &Context) -> bool {
+    input.states.iter()
+        .flat_map(|state| state.rules.iter().flat_map(rule_stmts))
+        .any(|stmt| matches!(stmt, BanishStmt::Rust(stmt) if contains_return(stmt)))
+}
+
+fn returns_value(input: &Context) -> bool {
+    input.states.iter()
+        .flat_map(|state| state.rules.iter().flat_map(rule_stmts))
+        .any(|stmt| match stmt {
+            BanishStmt::Rust(stmt) => find_returns(stmt).value,
+            _ => false,
+        })
+}
+
+// Wraps the value of every `return` that leaves the machine in `Ok`.
+// Closures and items have returns of their own, so they are left alone.
+struct WrapReturns;
+
+impl VisitMut for WrapReturns {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Closure(_) | Expr::Async(_) => {}
+            Expr::Return(ret) => {
+                if let Some(value) = &mut ret.expr {
+                    self.visit_expr_mut(value);
+                }
+                let value = ret.expr.take().map_or_else(|| quote! { () }, |value| quote! { #value });
+                ret.expr = Some(Box::new(syn::parse_quote! { ::std::result::Result::Ok(#value) }));
+            }
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _item: &mut syn::Item) {}
+}
+
+// Finds a `return` that leaves the machine, skipping closures and items just like `WrapReturns`.
+fn contains_return(stmt: &Stmt) -> bool {
+    find_returns(stmt).any
+}
+
+fn find_returns(stmt: &Stmt) -> FindReturn {
+    let mut finder: FindReturn = FindReturn { any: false, value: false };
+    finder.visit_stmt_mut(&mut stmt.clone());
+    finder
+}
+
+// Records whether a statement has a `return` that leaves the machine, and whether any of them carries a value.
+struct FindReturn {
+    any: bool,
+    value: bool,
+}
+
+impl VisitMut for FindReturn {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Closure(_) | Expr::Async(_) => {}
+            Expr::Return(ret) => {
+                self.any = true;
+                if let Some(value) = &mut ret.expr {
+                    self.value = true;
+                    self.visit_expr_mut(value);
+                }
+            }
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _item: &mut syn::Item) {}
 }
\ No newline at end of file